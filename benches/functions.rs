@@ -0,0 +1,73 @@
+//! Times building of a player from a dense 16-channel MIDI, which is mostly generation of
+//! math block functions of its channels.
+//!
+//! Compare sequential and parallel generation by running it without and with `rayon`:
+//! `cargo bench --bench functions` and `cargo bench --bench functions --features rayon`.
+
+use std::time::{Duration, Instant};
+use midly::{Format, Header, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use midi2swstruct::{build_music_players, encode_notes, DrumMode, NoteGroup, Pan, PlayerOptions};
+
+/// Times player is built, the median time is reported.
+const RUNS: usize = 11;
+
+/// MIDI with a track of `notes` short notes on every channel, pitches spread over 27-111.
+fn dense_midi(notes: usize) -> Smf<'static> {
+    let mut seed: u32 = 1;
+    let tracks = (0..16u8).map(|channel| {
+        let mut track = Vec::with_capacity(notes * 2);
+        for _ in 0..notes {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let key = 27 + (seed >> 16) as u8 % 85;
+            let note = |vel: u8| TrackEventKind::Midi {
+                channel: channel.into(),
+                message: MidiMessage::NoteOn { key: key.into(), vel: vel.into() }
+            };
+            track.push(TrackEvent { delta: 1.into(), kind: note(100) });
+            track.push(TrackEvent { delta: 3.into(), kind: note(0) });
+        }
+        track
+    }).collect();
+
+    Smf { header: Header::new(Format::Parallel, Timing::Metrical(96.into())), tracks }
+}
+
+fn main() {
+    let options = PlayerOptions::default().drums(DrumMode::Keep).notes_per_value(8);
+    let encoded = encode_notes(&dense_midi(20_000), &options).expect("failed to encode notes");
+    let group = NoteGroup {
+        name: None,
+        note_changes: encoded.note_changes,
+        pitches: encoded.pitches,
+        pan: Pan::Center,
+        pitch_bends: Vec::new()
+    };
+
+    let mut times: Vec<Duration> = Vec::with_capacity(RUNS);
+    let mut block_count = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let (_, stats) = build_music_players(
+            vec![group.clone()],
+            encoded.tempo_changes.clone(),
+            &encoded.time_signatures,
+            encoded.loop_range,
+            encoded.ppq,
+            encoded.total_len,
+            &options
+        ).expect("failed to build player");
+        times.push(start.elapsed());
+        block_count = stats.block_count;
+    }
+    times.sort();
+
+    println!(
+        "rayon: {}, threads: {}, channels: {}, blocks: {}, median: {:?}, min: {:?}",
+        cfg!(feature = "rayon"),
+        std::thread::available_parallelism().map_or(1, |n| n.get()),
+        group.note_changes.len(),
+        block_count,
+        times[RUNS / 2],
+        times[0]
+    );
+}
@@ -0,0 +1,398 @@
+//! Generation of math block expressions from value changes.
+//!
+//! Data is written as a sum of `step(t,x)` terms, where `x` is current time in ticks:
+//! a value changing by `d` at time `t` adds `d*step(t,x)`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Appends `coefficient*(step(t1,x)+step(t2,x)+...)` to function, omitting
+/// unneeded signs, unit coefficients and parentheses.
+fn write_step_term(function: &mut String, coefficient: i64, times: &[u32]) {
+    match coefficient {
+        1  => if !function.is_empty() { function.push('+'); },
+        -1 => function.push('-'),
+        c if c > 0 => {
+            if !function.is_empty() { function.push('+'); }
+            let _ = write!(function, "{}*", c);
+        },
+        c => { let _ = write!(function, "{}*", c); }
+    }
+
+    if times.len() > 1 { function.push('('); }
+    for (k, t) in times.iter().enumerate() {
+        if k > 0 { function.push('+'); }
+        let _ = write!(function, "step({},x)", t);
+    }
+    if times.len() > 1 { function.push(')'); }
+}
+
+/// Upper bound of `+coefficient*step(time,x)` length for any coefficient.
+const MAX_STEP_TERM_LEN: usize = "-4294967295*step(4294967295,x)".len();
+
+fn digits(n: u64) -> usize {
+    n.checked_ilog10().map_or(1, |d| d as usize + 1)
+}
+
+/// Splits sorted data changes into chunks of at most `max_events_per_func` events each.
+///
+/// If `max_chars_per_func` is set, chunks are also cut so that the function generated from
+/// them can't be longer than that. Length is estimated by the worst case where every change
+/// is written as its own term, so functions may end up a little shorter than the limit.
+/// A chunk always has at least one change.
+pub(crate) fn split_changes(data_changes: &[(u32, u32)], max_events_per_func: usize, max_chars_per_func: Option<usize>) -> Vec<&[(u32, u32)]> {
+    let max_events = max_events_per_func.max(1);
+    let max_chars = max_chars_per_func.unwrap_or(usize::MAX);
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut chars = 0;
+    let mut prev_data = 0;
+
+    for (i, &(time, data)) in data_changes.iter().enumerate() {
+        let diff = (data as i64 - prev_data as i64).unsigned_abs();
+        // '+' or '-', coefficient, '*', 'step(', time, ',x)'
+        let term_len = 1 + digits(diff) + 1 + 5 + digits(time as u64) + 3;
+
+        // Room for the term and for the closing term of the chunk.
+        if i > start && (i - start >= max_events || chars + term_len + MAX_STEP_TERM_LEN > max_chars) {
+            chunks.push(&data_changes[start..i]);
+            start = i;
+            chars = 0;
+        }
+
+        chars += if i == start { 1 + digits(data as u64) + 1 + 5 + digits(time as u64) + 3 } else { term_len };
+        prev_data = data;
+    }
+
+    if start < data_changes.len() {
+        chunks.push(&data_changes[start..]);
+    }
+
+    chunks
+}
+
+/// Writes sum of step terms for changes grouped by value difference: every
+/// `diff => [t1, t2, ...]` becomes `diff*(step(t1,x)+step(t2,x)+...)`.
+///
+/// Terms are ordered by difference and times in ascending order, so equal changes always
+/// give the same expression. Returns empty string if there are no changes.
+pub fn changes_to_expression(changes: &BTreeMap<i64, Vec<u32>>) -> String {
+    let mut function = String::new();
+    let mut sorted_times = Vec::new();
+    for (&diff, times) in changes {
+        if diff == 0 || times.is_empty() { continue; }
+        sorted_times.clone_from(times);
+        sorted_times.sort_unstable();
+        write_step_term(&mut function, diff, &sorted_times);
+    }
+    function
+}
+
+/// Splits sorted data changes into functions of at most `max_events_per_func` events
+/// and, if set, at most `max_chars_per_func` characters.
+///
+/// Every function starts from 0 and, unless it is the last one, returns to 0 at the time
+/// where the next function takes over, so the sum of all functions equals the original data.
+/// Changes at the same time are merged into one net change and times with equal change
+/// share one coefficient, so every time appears in at most one `step` per function.
+/// Output only depends on the changes, it is used for both note and tempo data.
+pub fn data_to_functions(mut data_changes: Vec<(u32, u32)>, max_events_per_func: usize, max_chars_per_func: Option<usize>) -> Vec<String> {
+    let mut functions: Vec<String> = Vec::new();
+    data_changes.sort_by_key(|e| e.0);
+
+    let chunks = split_changes(&data_changes, max_events_per_func, max_chars_per_func);
+
+    for (n, chunk) in chunks.iter().enumerate() {
+        let mut prev_data = 0;
+        let mut diffs: BTreeMap<i64, Vec<u32>> = BTreeMap::new();
+
+        // Only the last value at each time matters.
+        for same_time in chunk.chunk_by(|a, b| a.0 == b.0) {
+            let (time, data) = same_time[same_time.len() - 1];
+            let diff = data as i64 - prev_data as i64;
+
+            if diff != 0 {
+                diffs.entry(diff).or_default().push(time);
+            }
+
+            prev_data = data;
+        }
+
+        let mut function = changes_to_expression(&diffs);
+
+        // Handing the value over to the next function at its first event.
+        if let Some(&(next_time, _)) = chunks.get(n + 1).and_then(|c| c.first())
+            && prev_data != 0
+        {
+            write_step_term(&mut function, -(prev_data as i64), &[next_time]);
+        }
+
+        if function.is_empty() {
+            function.push('0');
+        }
+
+        functions.push(function);
+    }
+
+    functions
+}
+
+/// Builds function of decoder math block, which unpacks value of `bits` notes into outputs.
+///
+/// Input `A` is the packed value divided by `2^bits` (see data blocks), so its bits are
+/// binary fraction digits. Output `ind(bits-p)` is `step(0.5,(A%1/2^p)*2^p)`, the digit
+/// `p+1` places after the point, which is bit `bits-1-p` of the value. So `ind(k)` plays the
+/// note of bit `k-1`, the tone generator connected `k`-th. `step(1,A)` turns every output on
+/// for inputs of 1 and above.
+pub fn build_decoder_function(bits: usize) -> String {
+    build_layered_decoder_function(bits, 1)
+}
+
+/// [`build_decoder_function`] for `notes` notes of `bits_per_note` bits each.
+///
+/// Output of a note is its level (bits of the note read as a number, most significant
+/// first) divided by the maximal level `2^bits_per_note - 1`, so it is 1 for the loudest
+/// level, as in single bit decoder. For one bit per note the function is the same as
+/// [`build_decoder_function`].
+pub fn build_layered_decoder_function(notes: usize, bits_per_note: usize) -> String {
+    let bits_per_note = bits_per_note.max(1);
+    let max_level = (1u64 << bits_per_note) - 1;
+    let mut function = String::new();
+    for (n, ind) in (1..=notes).rev().enumerate() {
+        let _ = write!(function, "ind({})=", ind);
+        if bits_per_note > 1 { function.push('('); }
+        for b in 0..bits_per_note {
+            let p = n * bits_per_note + b;
+            if b > 0 { function.push('+'); }
+            let weight = 1u64 << (bits_per_note - 1 - b);
+            if weight > 1 { let _ = write!(function, "{}*", weight); }
+            let _ = write!(function, "step(0.5,(A%1/2^{})*2^{})", p, p);
+        }
+        if bits_per_note > 1 { let _ = write!(function, ")/{}", max_level); }
+        function.push_str("+step(1,A);");
+    }
+    function.push('0');
+    function
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Recursive descent parser of math block expressions over `f32`, as the game computes them.
+    struct Parser<'a> {
+        chars: Vec<char>,
+        pos: usize,
+        vars: &'a HashMap<String, f32>
+    }
+
+    impl Parser<'_> {
+        fn peek(&self) -> Option<char> {
+            self.chars.get(self.pos).copied()
+        }
+
+        fn eat(&mut self, c: char) -> bool {
+            let eaten = self.peek() == Some(c);
+            if eaten { self.pos += 1; }
+            eaten
+        }
+
+        fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+            let start = self.pos;
+            while self.peek().is_some_and(&f) { self.pos += 1; }
+            self.chars[start..self.pos].iter().collect()
+        }
+
+        fn sum(&mut self) -> f32 {
+            let mut value = self.modulo();
+            loop {
+                if self.eat('+') { value += self.modulo(); }
+                else if self.eat('-') { value -= self.modulo(); }
+                else { return value; }
+            }
+        }
+
+        fn modulo(&mut self) -> f32 {
+            let mut value = self.product();
+            while self.eat('%') { value = value.rem_euclid(self.product()); }
+            value
+        }
+
+        fn product(&mut self) -> f32 {
+            let mut value = self.unary();
+            loop {
+                if self.eat('*') { value *= self.unary(); }
+                else if self.eat('/') { value /= self.unary(); }
+                else { return value; }
+            }
+        }
+
+        fn unary(&mut self) -> f32 {
+            if self.eat('-') { return -self.unary(); }
+            let base = self.atom();
+            if self.eat('^') { base.powf(self.unary()) } else { base }
+        }
+
+        fn atom(&mut self) -> f32 {
+            if self.eat('(') {
+                let value = self.sum();
+                assert!(self.eat(')'), "missing ')' at {}", self.pos);
+                return value;
+            }
+            if self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                return self.take_while(|c| c.is_ascii_digit() || c == '.').parse().unwrap();
+            }
+
+            let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !self.eat('(') {
+                return *self.vars.get(&name).unwrap_or_else(|| panic!("unknown variable '{}'", name));
+            }
+            let mut args = vec![self.sum()];
+            while self.eat(',') { args.push(self.sum()); }
+            assert!(self.eat(')'), "missing ')' at {}", self.pos);
+            match (name.as_str(), &args[..]) {
+                ("step", &[edge, x]) => if x >= edge { 1.0 } else { 0.0 },
+                ("max", &[a, b]) => a.max(b),
+                ("min", &[a, b]) => a.min(b),
+                _ => panic!("unknown function '{}' of {} arguments", name, args.len())
+            }
+        }
+    }
+
+    /// Evaluates math block function with inputs `vars`, returns value of the block and
+    /// outputs `ind(k)` set by it.
+    ///
+    /// Statements are separated by `;`, `name=expr` assigns a variable, `ind(k)=expr` sets
+    /// output `k` and the last statement is the value of the block. `%` binds weaker than
+    /// `*` and `/` (decoders rely on `A%1/2^p` being `A%(1/2^p)`) and `step(e,v)` is 1 for
+    /// `v >= e`, 0 otherwise.
+    pub(crate) fn eval(function: &str, vars: &[(&str, f32)]) -> (f32, HashMap<usize, f32>) {
+        let mut vars: HashMap<String, f32> = vars.iter().map(|&(n, v)| (n.to_string(), v)).collect();
+        let mut outputs = HashMap::new();
+        let mut value = 0.0;
+
+        for statement in function.split(';') {
+            let (target, expr) = match statement.split_once('=') {
+                Some((target, expr)) => (Some(target), expr),
+                None => (None, statement)
+            };
+            let result = {
+                let mut parser = Parser { chars: expr.chars().filter(|c| !c.is_whitespace()).collect(), pos: 0, vars: &vars };
+                let result = parser.sum();
+                assert_eq!(parser.pos, parser.chars.len(), "unexpected '{}' in '{}'", parser.chars[parser.pos], expr);
+                result
+            };
+            match target.map(|t| t.strip_prefix("ind(").and_then(|t| t.strip_suffix(')')).ok_or(t)) {
+                Some(Ok(index)) => { outputs.insert(index.parse().unwrap(), result); },
+                Some(Err(name)) => { vars.insert(name.to_string(), result); },
+                None => value = result
+            }
+        }
+
+        (value, outputs)
+    }
+
+    /// Value of data split by [`data_to_functions`] at time `x`, the sum of all functions.
+    fn eval_functions(functions: &[String], x: f32) -> f32 {
+        functions.iter().map(|f| eval(f, &[("x", x)]).0).sum()
+    }
+
+    /// Value set by the last of sorted `changes` at or before time `x`.
+    fn value_at(changes: &[(u32, u32)], x: f32) -> f32 {
+        changes.iter().rev().find(|c| c.0 as f32 <= x).map_or(0.0, |c| c.1 as f32)
+    }
+
+    #[test]
+    fn chunked_functions_sum_to_the_same_data() {
+        let changes = vec![(0, 5), (10, 7), (10, 3), (20, 0), (25, 12), (26, 12), (41, 1), (60, 0), (61, 8)];
+
+        for max_events_per_func in [1, 2, changes.len()] {
+            let functions = data_to_functions(changes.clone(), max_events_per_func, None);
+            assert_eq!(functions.len(), changes.len().div_ceil(max_events_per_func));
+
+            for x in (0..140).map(|i| i as f32 / 2.0) {
+                assert_eq!(
+                    eval_functions(&functions, x),
+                    value_at(&changes, x),
+                    "max_events_per_func {}, x {}: {:?}", max_events_per_func, x, functions
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn factored_function_evaluates_like_one_step_per_change() {
+        // Changes of equal size at several times and two changes at the same time.
+        let changes = vec![(0, 1), (5, 0), (10, 1), (15, 0), (20, 1), (20, 3), (30, 0), (35, 2)];
+        let functions = data_to_functions(changes.clone(), usize::MAX, None);
+        assert_eq!(functions.len(), 1);
+        let factored = &functions[0];
+
+        let mut prev_data = 0;
+        let unfactored: String = changes.iter().map(|&(time, data)| {
+            let diff = data as i64 - prev_data;
+            prev_data = data as i64;
+            format!("{:+}*step({},x)", diff, time)
+        }).collect();
+        let unfactored = unfactored.trim_start_matches('+');
+
+        assert!(factored.len() < unfactored.len(), "{} is not shorter than {}", factored, unfactored);
+        assert_eq!(factored.matches("step(20,x)").count(), 1, "{}", factored);
+        for x in (0..80).map(|i| i as f32 / 2.0) {
+            assert_eq!(eval(factored, &[("x", x)]).0, eval(unfactored, &[("x", x)]).0, "x {}: {}", x, factored);
+        }
+    }
+
+    #[test]
+    fn expression_of_no_changes_is_empty() {
+        assert_eq!(changes_to_expression(&BTreeMap::new()), "");
+        assert_eq!(changes_to_expression(&BTreeMap::from([(0, vec![5]), (3, vec![])])), "");
+    }
+
+    #[test]
+    fn expression_of_single_change() {
+        assert_eq!(changes_to_expression(&BTreeMap::from([(1, vec![10])])), "step(10,x)");
+        assert_eq!(changes_to_expression(&BTreeMap::from([(-1, vec![10])])), "-step(10,x)");
+        assert_eq!(changes_to_expression(&BTreeMap::from([(12, vec![10])])), "12*step(10,x)");
+        assert_eq!(changes_to_expression(&BTreeMap::from([(-12, vec![10])])), "-12*step(10,x)");
+    }
+
+    #[test]
+    fn expression_of_overlapping_times() {
+        // Time 10 has changes of several sizes, times of one size are sorted.
+        let changes = BTreeMap::from([(3, vec![20, 10]), (-2, vec![10]), (1, vec![30, 10, 5])]);
+        assert_eq!(
+            changes_to_expression(&changes),
+            "-2*step(10,x)+(step(5,x)+step(10,x)+step(30,x))+3*(step(10,x)+step(20,x))"
+        );
+    }
+
+    /// Outputs `ind(1..=notes)` of decoder `function` for packed `value` of `bits` bits,
+    /// which data blocks pass to it as `value / 2^bits`.
+    fn decode(function: &str, value: u32, bits: usize, notes: usize) -> Vec<f32> {
+        let (_, outputs) = eval(function, &[("A", value as f32 / 2f32.powi(bits as i32))]);
+        (1..=notes).map(|k| outputs[&k]).collect()
+    }
+
+    #[test]
+    fn decoder_recovers_every_bit() {
+        for bits in [1, 3, 24] {
+            let decoder = build_decoder_function(bits);
+            let top = 1u32 << (bits - 1);
+            let values = [0, 1, top, top - 1, top | 1, (top << 1) - 1, 0x00A5_5A5A & ((top << 1) - 1)];
+
+            for value in values {
+                let expected: Vec<f32> = (0..bits).map(|b| (value >> b & 1) as f32).collect();
+                assert_eq!(decode(&decoder, value, bits, bits), expected, "value {:#b} of {} bits", value, bits);
+            }
+        }
+    }
+
+    #[test]
+    fn decoder_turns_every_output_on_at_1() {
+        let (_, outputs) = eval(&build_decoder_function(24), &[("A", 1.0)]);
+        assert!((1..=24).all(|k| outputs[&k] == 1.0), "{:?}", outputs);
+    }
+}
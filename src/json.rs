@@ -0,0 +1,136 @@
+//! JSON representation of generated buildings for inspection and diffing.
+//!
+//! Only fields set by the generator are written: block id, name, position, connections,
+//! metadata values and math block function. Other fields are left at their defaults by
+//! the generator and are omitted. JSON does not depend on structure version, it describes
+//! the building before serialization, so `--structure-version` has no effect on it.
+
+use std::fmt::Write;
+use sw_structure_io::structs::{Building, TypeSettings};
+
+/// Escapes string for use inside JSON string literal.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"'  => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(escaped, "\\u{:04x}", c as u32); },
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/// Writes float as JSON number (JSON has no NaN or infinity, they are written as `null`).
+fn write_f32(out: &mut String, value: f32) {
+    if value.is_finite() {
+        let _ = write!(out, "{}", value);
+    } else {
+        out.push_str("null");
+    }
+}
+
+fn write_f32_list(out: &mut String, values: &[f32]) {
+    out.push('[');
+    for (i, &v) in values.iter().enumerate() {
+        if i > 0 { out.push(','); }
+        write_f32(out, v);
+    }
+    out.push(']');
+}
+
+/// Serializes building into pretty-printed JSON, one block per line.
+pub fn building_to_json(building: &Building) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "{{");
+    let _ = writeln!(out, "  \"root_count\": {},", building.roots.len());
+    let _ = writeln!(out, "  \"blocks\": [");
+
+    for (i, block) in building.blocks.iter().enumerate() {
+        let _ = write!(out, "    {{\"index\":{},\"id\":{},\"name\":\"{}\",\"position\":", i, block.id, escape(&block.name));
+        write_f32_list(&mut out, &block.position);
+
+        out.push_str(",\"connections\":[");
+        for (j, c) in block.connections.iter().enumerate() {
+            if j > 0 { out.push(','); }
+            let _ = write!(out, "{}", c);
+        }
+        out.push(']');
+
+        if let Some(metadata) = &block.metadata {
+            out.push_str(",\"values\":");
+            write_f32_list(&mut out, &metadata.values);
+
+            if let TypeSettings::MathBlock { function, .. } = &metadata.type_settings {
+                let _ = write!(out, ",\"function\":\"{}\"", escape(function));
+            }
+        }
+
+        out.push('}');
+        if i + 1 < building.blocks.len() { out.push(','); }
+        out.push('\n');
+    }
+
+    let _ = writeln!(out, "  ]");
+    let _ = writeln!(out, "}}");
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sw_structure_io::structs::{Root, Block, Metadata};
+
+    #[test]
+    fn building_is_serialized_with_escaped_names_and_null_for_non_finite_values() {
+        let building = Building {
+            roots: vec![Root::default()],
+            blocks: vec![
+                Block {
+                    id: 78,
+                    name: "say \"hi\"\\\n\u{1}".into(),
+                    position: [-1.0, 0.5, 0.0],
+                    connections: vec![1, 2],
+                    ..Default::default()
+                },
+                Block {
+                    id: 125,
+                    metadata: Some(Metadata {
+                        values: vec![f32::NAN, f32::INFINITY, 100.0],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                Block {
+                    id: 129,
+                    metadata: Some(Metadata {
+                        type_settings: TypeSettings::MathBlock {
+                            function: "x=A*\t2".into(),
+                            incoming_connections_order: Vec::new(),
+                            slots: Vec::new()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }
+            ]
+        };
+
+        assert_eq!(building_to_json(&building), concat!(
+            "{\n",
+            "  \"root_count\": 1,\n",
+            "  \"blocks\": [\n",
+            "    {\"index\":0,\"id\":78,\"name\":\"say \\\"hi\\\"\\\\\\n\\u0001\",\"position\":[-1,0.5,0],\"connections\":[1,2]},\n",
+            "    {\"index\":1,\"id\":125,\"name\":\"\",\"position\":[0,0,0],\"connections\":[],\"values\":[null,null,100]},\n",
+            "    {\"index\":2,\"id\":129,\"name\":\"\",\"position\":[0,0,0],\"connections\":[],\"values\":[],\"function\":\"x=A*\\t2\"}\n",
+            "  ]\n",
+            "}\n"
+        ));
+    }
+}
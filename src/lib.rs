@@ -1,116 +1,887 @@
 use std::collections::{HashMap};
+use std::sync::Arc;
 use std::fmt::Write;
-use midly::{Smf, TrackEventKind, MidiMessage, Timing, MetaMessage};
+use midly::{Smf, Header, Format, TrackEvent, TrackEventKind, MidiMessage, Timing, MetaMessage, num::u28};
 use sw_structure_io::structs::{Root, Block, Building, Metadata, TypeSettings};
 use thiserror::Error;
 
+pub mod codegen;
+pub mod json;
+
+pub use codegen::{build_decoder_function, build_layered_decoder_function, data_to_functions};
+use codegen::split_changes;
+
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("formatting error")]
+    #[error("failed to format math block function")]
     Format(#[from] std::fmt::Error),
-    #[error("int conversion error")]
+    #[error("value doesn't fit in 16 bits ({0}): building has more than 65535 blocks (split it with max_blocks_per_building) or ticks per quarter note are too big for MIDI header")]
     FromInt(#[from] std::num::TryFromIntError),
-    #[error("unsupported timing")]
-    UnsupportedTimingSMPTE
+    #[error("SMPTE timing at {fps} fps is not supported, re-export MIDI with metrical timing (ticks per quarter note)")]
+    UnsupportedTimingSMPTE { fps: f32 },
+    #[error("no playable notes in pitch range {min_pitch}..={max_pitch}, try a wider range or other channel, drum or velocity settings")]
+    NoPlayableNotes { min_pitch: u8, max_pitch: u8 },
+    #[error("song is too long ({ticks} ticks, at most {max} are supported)", max = u32::MAX)]
+    SongTooLong { ticks: u64 },
+    #[error("gap of {ticks} ticks between MIDI events is longer than delta time can hold ({max})", max = u28::max_value())]
+    GapTooLong { ticks: u32 },
+    #[error("block {block} is connected to nonexistent block {target}")]
+    DanglingConnection { block: usize, target: u16 },
+    #[error("{notes_per_value} notes per value is more than math block can hold ({MAX_NOTES_PER_VALUE})")]
+    NotesPerValueTooLarge { notes_per_value: u8 },
+    #[error("{notes_per_value} notes of {bits_per_note} bits are more than math block can hold ({MAX_NOTES_PER_VALUE} bits), use fewer notes per value")]
+    ValueBitsTooLarge { notes_per_value: u8, bits_per_note: u8 },
+    #[error("invalid block palette {palette:?}: ids must be non-zero and distinct")]
+    InvalidBlockPalette { palette: BlockPalette },
+    #[error("time range {start}..{end} is empty or after the end of the song")]
+    EmptyTimeRange { start: u32, end: u32 }
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
-fn pitch_to_freq(midi: u8) -> f32 {
-    440.0 * 2.0_f32.powf((midi as f32 - 69.0) / 12.0)
+/// Maximal amount of notes packed into one value.
+///
+/// Math block works with 32-bit floats, which hold integers exactly only up to 2^24
+/// (24 bits of mantissa). Packed values with more bits would lose notes.
+pub const MAX_NOTES_PER_VALUE: u8 = 24;
+
+/// MIDI channel with percussion (channel 10, zero-based 9).
+pub const DRUM_CHANNEL: u8 = 9;
+
+/// Ids of blocks the player is built from. Defaults to ids of the game version
+/// [`build_music_player`] was written for, other versions or mods may need other ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockPalette {
+    /// Math block (main block, decoders, data blocks, measure marker).
+    pub math: u8,
+    /// Switch that starts the player.
+    pub switch: u8,
+    /// OR gate (inputs and outputs of math blocks, group outputs).
+    pub or: u8,
+    /// Tone generator.
+    pub tone_generator: u8
 }
 
-pub fn data_to_functions(mut data_changes: Vec<(u32, u32)>, max_events_per_func: usize) -> Vec<String> {
-    let mut functions: Vec<String> = Vec::new();
-    data_changes.sort_by_key(|e| e.0);
+impl Default for BlockPalette {
+    fn default() -> Self {
+        Self {
+            math: 129,
+            switch: 9,
+            or: 78,
+            tone_generator: 125
+        }
+    }
+}
 
-    let mut i = 0;
-    let mut prev_data = 0;
-    let mut diffs: HashMap<i64, Vec<u32>> = HashMap::new();
+impl BlockPalette {
+    /// Checks that all ids are non-zero and no two kinds of blocks share an id.
+    pub fn validate(&self) -> Result<()> {
+        let ids = [self.math, self.switch, self.or, self.tone_generator];
+        let distinct = ids.iter().enumerate().all(|(i, id)| !ids[..i].contains(id));
+        if ids.contains(&0) || !distinct {
+            return Err(Error::InvalidBlockPalette { palette: *self });
+        }
+        Ok(())
+    }
+}
 
-    for (n, &(time, data)) in data_changes.iter().enumerate() {
-        i += 1;
-        if i > max_events_per_func || n + 1 == data_changes.len() {
-            let mut function = String::new();
+/// MIDI controller number of sustain pedal.
+const SUSTAIN_CONTROLLER: u8 = 64;
 
-            for (j, (&diff, time_keys)) in diffs.iter().enumerate() {
-                if j > 0 { function.push('+'); }
+/// MIDI controller number of pan.
+const PAN_CONTROLLER: u8 = 10;
 
-                let _ = write!(function, "{}*(", diff);
+/// Distance of tone generators of left and right groups from the center.
+pub const STEREO_SPREAD: f32 = 1.0;
 
-                for (k, t) in time_keys.iter().enumerate() {
-                    if k > 0 { function.push('+'); }
-                    let _ = write!(function, "step({},x)", t);
-                }
+/// Distance of MIDI pan value from the center (64) still played in the center, so only
+/// channels panned at least a quarter of the way to a side are moved to it.
+pub const PAN_DEAD_ZONE: u8 = 16;
+
+/// Side of stereo image a group of notes is played on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Pan {
+    /// Middle, the only side in mono mode.
+    #[default]
+    Center,
+    /// Left of the player.
+    Left,
+    /// Right of the player.
+    Right
+}
+
+impl Pan {
+    /// Side of MIDI pan value (0-127, 64 is center). Values within [`PAN_DEAD_ZONE`] of
+    /// the center are centered, values further to a side play like hard left (0) or hard
+    /// right (127).
+    pub fn from_midi(value: u8) -> Self {
+        match value {
+            v if v < 64 - PAN_DEAD_ZONE => Pan::Left,
+            v if v > 64 + PAN_DEAD_ZONE => Pan::Right,
+            _ => Pan::Center
+        }
+    }
+
+    /// X offset of tone generators on this side.
+    fn offset(self) -> f32 {
+        match self {
+            Pan::Center => 0.0,
+            Pan::Left   => -STEREO_SPREAD,
+            Pan::Right  => STEREO_SPREAD
+        }
+    }
+}
+
+/// How notes on the drum channel are converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DrumMode {
+    /// Drum channel is ignored.
+    #[default]
+    Skip,
+    /// Drum notes are replaced with pitches from [`drum_to_pitch`].
+    Map,
+    /// Drum notes are played as regular pitches.
+    Keep
+}
+
+impl std::str::FromStr for DrumMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(DrumMode::Skip),
+            "map"  => Ok(DrumMode::Map),
+            "keep" => Ok(DrumMode::Keep),
+            _ => Err(format!("unknown drum mode '{}', expected skip, map or keep", s))
+        }
+    }
+}
+
+/// Which note is cut when more than `max_polyphony` notes would sound at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VoiceStealing {
+    /// Note that started first is cut.
+    #[default]
+    Oldest,
+    /// Note with the lowest velocity is cut, the oldest of them if several are equally quiet.
+    /// New note quieter than all sounding ones isn't played.
+    LowestVelocity
+}
+
+impl std::str::FromStr for VoiceStealing {
+    type Err = String;
 
-                function.push(')');
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "oldest"          => Ok(VoiceStealing::Oldest),
+            "lowest-velocity" => Ok(VoiceStealing::LowestVelocity),
+            _ => Err(format!("unknown voice stealing policy '{}', expected oldest or lowest-velocity", s))
+        }
+    }
+}
+
+/// Maps General MIDI percussion note to a pitch that resembles its sound.
+///
+/// | Drum notes                              | Pitch     |
+/// | --------------------------------------- | --------- |
+/// | 35, 36 (bass drums)                     | 28 (E1)   |
+/// | 37, 38, 39, 40 (stick, snares, clap)    | 50 (D3)   |
+/// | 41, 43 (floor toms)                     | 41 (F2)   |
+/// | 45, 47 (low and low-mid toms)           | 45 (A2)   |
+/// | 48, 50 (high-mid and high toms)         | 48 (C3)   |
+/// | 42, 44, 46 (hi-hats)                    | 90 (F#6)  |
+/// | 49, 52, 55, 57 (crash, china, splash)   | 86 (D6)   |
+/// | 51, 53, 59 (rides)                      | 84 (C6)   |
+/// | 54 (tambourine)                         | 93 (A6)   |
+/// | 56 (cowbell)                            | 68 (G#4)  |
+///
+/// Other notes have no mapping.
+pub fn drum_to_pitch(key: u8) -> Option<u8> {
+    match key {
+        35 | 36           => Some(28),
+        37..=40           => Some(50),
+        41 | 43           => Some(41),
+        45 | 47           => Some(45),
+        48 | 50           => Some(48),
+        42 | 44 | 46      => Some(90),
+        49 | 52 | 55 | 57 => Some(86),
+        51 | 53 | 59      => Some(84),
+        54                => Some(93),
+        56                => Some(68),
+        _ => None
+    }
+}
+
+/// Converts MIDI pitch to frequency, `a4_hz` being the frequency of A4 (pitch 69).
+pub fn pitch_to_freq(midi: u8, a4_hz: f32) -> f32 {
+    a4_hz * 2.0_f32.powf((midi as f32 - 69.0) / 12.0)
+}
+
+/// Defers NoteOffs on channels where sustain pedal is held until the pedal is released.
+///
+/// Expects note events `(time, channel, key, velocity)` sorted by time, velocity 0 being NoteOff. Pedal events
+/// `(time, channel, is_down)` are applied before note events at the same time.
+/// Notes still held by the pedal at the end are released at `end`.
+fn apply_sustain(note_events: Vec<(u32, u8, u8, u8)>, mut pedal_events: Vec<(u32, u8, bool)>, end: u32) -> Vec<(u32, u8, u8, u8)> {
+    if pedal_events.is_empty() {
+        return note_events;
+    }
+    pedal_events.sort_by_key(|e| e.0);
+
+    let mut result = Vec::with_capacity(note_events.len());
+    let mut pedal_down = [false; 16];
+    let mut held: Vec<Vec<u8>> = vec![Vec::new(); 16];
+
+    let mut pedals = pedal_events.into_iter().peekable();
+    let mut notes = note_events.into_iter().peekable();
+
+    loop {
+        let pedal_first = match (pedals.peek(), notes.peek()) {
+            (Some(p), Some(n)) => p.0 <= n.0,
+            (Some(_), None)    => true,
+            (None, Some(_))    => false,
+            (None, None)       => break
+        };
+
+        if pedal_first {
+            let Some((time, channel, down)) = pedals.next() else { break };
+            pedal_down[channel as usize] = down;
+            if !down {
+                for key in held[channel as usize].drain(..) {
+                    result.push((time, channel, key, 0));
+                }
+            }
+        } else {
+            let Some(event) = notes.next() else { break };
+            let (_, channel, key, vel) = event;
+            if vel == 0 && pedal_down[channel as usize] {
+                held[channel as usize].push(key);
+            } else {
+                result.push(event);
             }
+        }
+    }
+
+    for (channel, keys) in held.into_iter().enumerate() {
+        for key in keys {
+            result.push((end, channel as u8, key, 0));
+        }
+    }
+
+    result
+}
 
-            let _ = write!(function, "-{}*step({},x)", prev_data, time);
+/// Tempo of MIDI without tempo events in microseconds per quarter note (120 BPM).
+pub const DEFAULT_TEMPO: u32 = 500_000;
+
+/// Minimal tempo in microseconds per quarter note (60000 BPM), smaller tempos are clamped
+/// to it so that broken tempo events (like tempo of 0) can't stall or break the player.
+pub const MIN_TEMPO: u32 = 1_000;
+
+/// Text of MIDI marker starting the looped section.
+pub const LOOP_START_MARKER: &str = "loopStart";
+/// Text of MIDI marker ending the looped section.
+pub const LOOP_END_MARKER: &str = "loopEnd";
+
+/// Function deciding whether NoteOn `(channel, key, velocity)` is played,
+/// see [`PlayerOptions::velocity_gate`].
+#[derive(Clone)]
+pub struct VelocityGate(Arc<dyn Fn(u8, u8, u8) -> bool + Send + Sync>);
+
+impl std::fmt::Debug for VelocityGate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("VelocityGate")
+    }
+}
+
+impl PartialEq for VelocityGate {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
 
-            functions.push(function);
+/// Options of MIDI conversion and generated player.
+///
+/// Created with [`Default`] (same defaults as the command line tool) and changed with
+/// builder-style setters:
+///
+/// ```
+/// use midi2swstruct::PlayerOptions;
+///
+/// let options = PlayerOptions::default().repeat(true).notes_per_value(12);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerOptions {
+    notes_per_value: u8,
+    min_pitch: u8,
+    max_pitch: u8,
+    min_velocity: u8,
+    velocity_gate: Option<VelocityGate>,
+    repeat: bool,
+    max_events_per_func: usize,
+    max_chars_per_func: Option<usize>,
+    a4_hz: f32,
+    master_volume: f32,
+    channels: Option<Vec<u8>>,
+    drums: DrumMode,
+    pitch_bend_range: u8,
+    group_by_track: bool,
+    emit_beat_marker: bool,
+    fixed_tempo_bpm: Option<f32>,
+    hold_last: bool,
+    dedupe_tones: bool,
+    max_blocks_per_building: Option<usize>,
+    collect_diagnostics: bool,
+    max_polyphony: Option<usize>,
+    voice_stealing: VoiceStealing,
+    lead_in_ticks: u32,
+    palette: BlockPalette,
+    stereo: bool,
+    start_ticks: u32,
+    end_ticks: Option<u32>,
+    bits_per_note: u8
+}
 
-            i = 0;
-            prev_data = 0;
-            diffs = HashMap::new();
+impl Default for PlayerOptions {
+    fn default() -> Self {
+        Self {
+            notes_per_value: 24,
+            min_pitch: 27,
+            max_pitch: 111,
+            min_velocity: 1,
+            velocity_gate: None,
+            repeat: false,
+            max_events_per_func: 1024,
+            max_chars_per_func: None,
+            a4_hz: 440.0,
+            master_volume: MAX_VOLUME,
+            channels: None,
+            drums: DrumMode::Skip,
+            pitch_bend_range: 2,
+            group_by_track: false,
+            emit_beat_marker: false,
+            fixed_tempo_bpm: None,
+            hold_last: false,
+            dedupe_tones: false,
+            max_blocks_per_building: None,
+            collect_diagnostics: false,
+            max_polyphony: None,
+            voice_stealing: VoiceStealing::Oldest,
+            lead_in_ticks: 0,
+            palette: BlockPalette::default(),
+            stereo: false,
+            start_ticks: 0,
+            end_ticks: None,
+            bits_per_note: 1
         }
+    }
+}
+
+impl PlayerOptions {
+    /// How many notes are packed into one value, at most [`MAX_NOTES_PER_VALUE`].
+    pub fn notes_per_value(mut self, notes_per_value: u8) -> Self {
+        self.notes_per_value = notes_per_value;
+        self
+    }
+
+    /// Minimal pitch of played notes.
+    pub fn min_pitch(mut self, min_pitch: u8) -> Self {
+        self.min_pitch = min_pitch;
+        self
+    }
+
+    /// Maximal pitch of played notes.
+    pub fn max_pitch(mut self, max_pitch: u8) -> Self {
+        self.max_pitch = max_pitch;
+        self
+    }
+
+    /// Minimal velocity of played notes, quieter notes are ignored.
+    pub fn min_velocity(mut self, min_velocity: u8) -> Self {
+        self.min_velocity = min_velocity;
+        self
+    }
+
+    /// Replaces [`Self::min_velocity`] check with `gate(channel, key, velocity)`, NoteOns
+    /// it returns `false` for are ignored together with their NoteOffs. Allows thresholds
+    /// per channel or pitch, e.g. for filtering ghost notes of drums. NoteOn with velocity 0
+    /// is a NoteOff and never reaches the gate.
+    ///
+    /// Without gate notes are played when `velocity >= min_velocity`, exactly as before.
+    pub fn velocity_gate(mut self, gate: impl Fn(u8, u8, u8) -> bool + Send + Sync + 'static) -> Self {
+        self.velocity_gate = Some(VelocityGate(Arc::new(gate)));
+        self
+    }
+
+    /// Whether the song starts over when it ends.
+    pub fn repeat(mut self, repeat: bool) -> Self {
+        self.repeat = repeat;
+        self
+    }
+
+    /// Maximal amount of events in one math block function.
+    pub fn max_events_per_func(mut self, max_events_per_func: usize) -> Self {
+        self.max_events_per_func = max_events_per_func;
+        self
+    }
+
+    /// Maximal length of math block function in characters, `None` for no limit.
+    pub fn max_chars_per_func(mut self, max_chars_per_func: Option<usize>) -> Self {
+        self.max_chars_per_func = max_chars_per_func;
+        self
+    }
+
+    /// Frequency of A4 in Hz.
+    pub fn a4_hz(mut self, a4_hz: f32) -> Self {
+        self.a4_hz = a4_hz;
+        self
+    }
+
+    /// Volume of tone generators, clamped to [`MAX_VOLUME`].
+    pub fn master_volume(mut self, master_volume: f32) -> Self {
+        self.master_volume = master_volume;
+        self
+    }
+
+    /// MIDI channels to convert, `None` for all channels.
+    pub fn channels(mut self, channels: Option<Vec<u8>>) -> Self {
+        self.channels = channels;
+        self
+    }
 
-        let diff = data as i64 - prev_data as i64;
+    /// Handling of notes on [`DRUM_CHANNEL`].
+    pub fn drums(mut self, drums: DrumMode) -> Self {
+        self.drums = drums;
+        self
+    }
+
+    /// Pitch bend range in semitones (2 by default), 0 ignores pitch bends.
+    /// See [`encode_notes`] and [`build_music_player`] for how bends are played.
+    pub fn pitch_bend_range(mut self, pitch_bend_range: u8) -> Self {
+        self.pitch_bend_range = pitch_bend_range;
+        self
+    }
+
+    /// Whether notes of every track get their own channels behind a named OR gate.
+    pub fn group_by_track(mut self, group_by_track: bool) -> Self {
+        self.group_by_track = group_by_track;
+        self
+    }
+
+    /// Whether to add measure marker block (see [`build_music_player`]). With `start_ticks`,
+    /// measures are counted from the start of the converted part.
+    pub fn emit_beat_marker(mut self, emit_beat_marker: bool) -> Self {
+        self.emit_beat_marker = emit_beat_marker;
+        self
+    }
+
+    /// Constant tempo in BPM used instead of tempo changes of the song.
+    pub fn fixed_tempo_bpm(mut self, fixed_tempo_bpm: Option<f32>) -> Self {
+        self.fixed_tempo_bpm = fixed_tempo_bpm;
+        self
+    }
+
+    /// Whether notes still playing at the end of non-repeating song keep playing.
+    pub fn hold_last(mut self, hold_last: bool) -> Self {
+        self.hold_last = hold_last;
+        self
+    }
+
+    /// Whether decoders of different groups playing the same pitch share one tone generator
+    /// instead of having one each. Saves blocks when tracks of [`Self::group_by_track`]
+    /// share pitches.
+    pub fn dedupe_tones(mut self, dedupe_tones: bool) -> Self {
+        self.dedupe_tones = dedupe_tones;
+        self
+    }
+
+    /// Maximal amount of blocks per building, `None` for no limit.
+    pub fn max_blocks_per_building(mut self, max_blocks_per_building: Option<usize>) -> Self {
+        self.max_blocks_per_building = max_blocks_per_building;
+        self
+    }
+
+    /// Whether notes dropped for being out of pitch range are collected into
+    /// [`EncodedNotes::dropped_notes`]. Off by default, so nothing is allocated for them.
+    pub fn collect_diagnostics(mut self, collect_diagnostics: bool) -> Self {
+        self.collect_diagnostics = collect_diagnostics;
+        self
+    }
+
+    /// Maximal amount of notes sounding at once in a group, `None` for no limit. Notes over
+    /// the limit cut notes chosen by [`Self::voice_stealing`]. Limit is at least 1.
+    pub fn max_polyphony(mut self, max_polyphony: Option<usize>) -> Self {
+        self.max_polyphony = max_polyphony.map(|n| n.max(1));
+        self
+    }
+
+    /// Which note is cut when [`Self::max_polyphony`] is exceeded.
+    pub fn voice_stealing(mut self, voice_stealing: VoiceStealing) -> Self {
+        self.voice_stealing = voice_stealing;
+        self
+    }
+
+    /// Silence in ticks added before the song, the whole timeline is shifted later by it.
+    /// Lead-in plays at the tempo the song starts with and is part of the loop when the
+    /// song has no loop markers.
+    pub fn lead_in_ticks(mut self, lead_in_ticks: u32) -> Self {
+        self.lead_in_ticks = lead_in_ticks;
+        self
+    }
+
+    /// Ids of generated blocks.
+    pub fn palette(mut self, palette: BlockPalette) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    /// Whether notes are split by pan of their MIDI channel into `left` and `right` groups,
+    /// with tone generators moved [`STEREO_SPREAD`] to that side. Channels panned near the
+    /// center (see [`Pan::from_midi`]) or without pan are played as in mono mode, which is
+    /// the default and is unchanged.
+    pub fn stereo(mut self, stereo: bool) -> Self {
+        self.stereo = stereo;
+        self
+    }
+
+    /// Start of the part of MIDI to convert in ticks. Notes sounding at it are played from
+    /// the beginning, times are shifted so that it becomes time 0.
+    pub fn start_ticks(mut self, start_ticks: u32) -> Self {
+        self.start_ticks = start_ticks;
+        self
+    }
 
-        diffs
-        .entry(diff)
-        .and_modify(|e| e.push(time))
-        .or_insert(vec![time]);
+    /// End of the part of MIDI to convert in ticks, `None` for the end of the song.
+    pub fn end_ticks(mut self, end_ticks: Option<u32>) -> Self {
+        self.end_ticks = end_ticks;
+        self
+    }
 
-        prev_data = data;
+    /// Bits every note takes in packed values, at least 1. Notes with more bits store velocity
+    /// in `2^bits_per_note - 1` levels and decoders output `level / (2^bits_per_note - 1)`
+    /// instead of 1, so louder notes get bigger outputs.
+    ///
+    /// A value holds `notes_per_value * bits_per_note` bits, at most [`MAX_NOTES_PER_VALUE`],
+    /// so more bits per note mean fewer notes per value and more decoders and data blocks.
+    pub fn bits_per_note(mut self, bits_per_note: u8) -> Self {
+        self.bits_per_note = bits_per_note.max(1);
+        self
     }
+}
+
+/// Why a note was dropped by [`encode_notes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Pitch is lower than `min_pitch`.
+    BelowMinPitch,
+    /// Pitch is higher than `max_pitch`.
+    AboveMaxPitch
+}
+
+/// Note that was not played, collected when [`PlayerOptions::collect_diagnostics`] is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DroppedNote {
+    /// Time of the NoteOn in ticks.
+    pub time: u32,
+    /// MIDI channel of the note.
+    pub channel: u8,
+    /// Pitch of the note (after drum mapping).
+    pub key: u8,
+    /// Why the note was dropped.
+    pub reason: DropReason
+}
+
+/// Note and tempo data of MIDI, packed for the music player.
+///
+/// Every used pitch gets an index in `pitches` (sorted ascending). Notes are packed into
+/// values by groups of `notes_per_value`: bit `b` of value `c` is set while the note
+/// `pitches[c * notes_per_value + b]` is playing. `note_changes[c]` lists `(time, value)`
+/// for every time value `c` changes. With `bits_per_note` above 1, note `b` takes bits from
+/// `b * bits_per_note` holding its velocity level instead of one bit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedNotes {
+    /// Ticks per quarter note.
+    pub ppq: u32,
+    /// Changes of packed note values, one list per value.
+    pub note_changes: Vec<Vec<(u32, u32)>>,
+    /// Pitch of every bit index.
+    pub pitches: Vec<u8>,
+    /// Tempo changes `(time, microseconds per quarter note)`, starting at time 0
+    /// ([`DEFAULT_TEMPO`] if MIDI has no tempo event at its start), at least [`MIN_TEMPO`].
+    pub tempo_changes: Vec<(u32, u32)>,
+    /// Time signature changes `(time, numerator, denominator as power of 2)`, sorted by time.
+    pub time_signatures: Vec<(u32, u8, u8)>,
+    /// Section `(start, end)` to loop, set by [`LOOP_START_MARKER`] and [`LOOP_END_MARKER`].
+    pub loop_range: Option<(u32, u32)>,
+    /// Pitch bend changes `(time, 14-bit bend)` of every MIDI channel, where
+    /// [`PITCH_BEND_CENTER`] is no bend. Lists of channels with pitch bend events start at
+    /// time 0, other lists are empty.
+    pub pitch_bends: [Vec<(u32, u32)>; 16],
+    /// Length of the song in ticks.
+    pub total_len: u32,
+    /// Notes out of pitch range, empty unless [`PlayerOptions::collect_diagnostics`] is set.
+    pub dropped_notes: Vec<DroppedNote>
+}
+
+/// Same as [`encode_notes`], but returns fields of [`EncodedNotes`] as a tuple
+/// `(ppq, note_changes, pitches, tempo_changes, total_len)`.
+#[allow(clippy::type_complexity)]
+pub fn midi_to_data_changes(smf: Smf, options: &PlayerOptions) -> Result<(u32, Vec<Vec<(u32, u32)>>, Vec<u8>, Vec<(u32, u32)>, u32)> {
+    let EncodedNotes { ppq, note_changes, pitches, tempo_changes, total_len, .. } = encode_notes(&smf, options)?;
+
+    Ok((ppq, note_changes, pitches, tempo_changes, total_len))
+}
 
-    functions
+/// Collects note and tempo changes from MIDI and packs note states into values (see [`EncodedNotes`]).
+///
+/// Only note options of `options` are used. NoteOn with velocity 0 is always treated as
+/// NoteOff. `min_velocity` (or `velocity_gate`) is a sensitivity threshold: quieter
+/// NoteOns are ignored together with their matching NoteOffs. Only events from `channels` are used, `None` means all
+/// channels. Notes on [`DRUM_CHANNEL`] are handled according to `drums`. NoteOffs are
+/// deferred while sustain pedal (CC64) is held on their channel.
+///
+/// Pitch bends don't change notes, they are collected per channel into `pitch_bends`
+/// (ignored if `pitch_bend_range` is 0), drum channel is never bent.
+///
+/// Tracks of MIDI format 2 are independent patterns, so they are played one after another
+/// instead of simultaneously.
+pub fn encode_notes(smf: &Smf, options: &PlayerOptions) -> Result<EncodedNotes> {
+    let tracks: Vec<(u64, &[TrackEvent])> = track_offsets(smf)
+    .into_iter()
+    .zip(smf.tracks.iter().map(|t| &t[..]))
+    .collect();
+
+    encode_tracks(smf.header.timing, &tracks, 0, options)
+}
+
+/// Time in ticks every track starts at: 0 for simultaneous tracks, end of the previous
+/// track for MIDI format 2 (sequential).
+fn track_offsets(smf: &Smf) -> Vec<u64> {
+    let mut offset = 0;
+    smf.tracks.iter().map(|track| {
+        let start = offset;
+        if smf.header.format == Format::Sequential {
+            offset += track.iter().map(|e| e.delta.as_int() as u64).sum::<u64>();
+        }
+        start
+    }).collect()
 }
 
-pub fn midi_to_data_changes(smf: Smf, min_pitch: u8, max_pitch: u8, min_velocity: u8, notes_per_value: u8) -> Result<(u32, Vec<Vec<(u32, u32)>>, Vec<u8>, Vec<(u32, u32)>, u32)> {
-    let ppq = match smf.header.timing {
+/// [`encode_notes`] for tracks `(start time, events)`. When they are a part of a song,
+/// `song_len` is its length in ticks (with lead-in), so they are clipped as the whole song.
+fn encode_tracks(timing: Timing, tracks: &[(u64, &[TrackEvent])], song_len: u32, options: &PlayerOptions) -> Result<EncodedNotes> {
+    let &PlayerOptions {
+        min_pitch,
+        max_pitch,
+        min_velocity,
+        notes_per_value,
+        drums,
+        pitch_bend_range,
+        lead_in_ticks,
+        start_ticks,
+        end_ticks,
+        bits_per_note,
+        ..
+    } = options;
+    let channels = options.channels.as_deref();
+    let passes_gate = |channel: u8, key: u8, vel: u8| match &options.velocity_gate {
+        Some(gate) => (gate.0)(channel, key, vel),
+        None => vel >= min_velocity
+    };
+
+    value_bits(options)?;
+
+    let ppq = match timing {
         Timing::Metrical(t) => t.as_int() as u32,
-        Timing::Timecode(_, _) => return Err(Error::UnsupportedTimingSMPTE)
+        Timing::Timecode(fps, _) => return Err(Error::UnsupportedTimingSMPTE { fps: fps.as_f32() })
     };
 
     // Collecting events from all tracks in midi.
-    let mut note_events: Vec<(u32, u8, bool)> = Vec::new();
-    let mut used_keys = [false; 128];
-    let mut total_len = 0;
+    // (time, channel, key, velocity), velocity 0 is NoteOff.
+    let mut note_events: Vec<(u32, u8, u8, u8)> = Vec::new();
+    let mut pedal_events: Vec<(u32, u8, bool)> = Vec::new();
+    let mut total_len = song_len;
     let mut tempo_events: Vec<(u32, u32)> = Vec::new();
+    let mut time_signatures: Vec<(u32, u8, u8)> = Vec::new();
+    let mut loop_start: Option<u32> = None;
+    let mut loop_end: Option<u32> = None;
+
+    let mut pitch_bends: [Vec<(u32, u32)>; 16] = Default::default();
+
+    let mut dropped_notes: Vec<DroppedNote> = Vec::new();
+
+    let mut push_note = |time: u32, channel: u8, key: u8, vel: u8| {
+        if key < min_pitch || key > max_pitch {
+            if vel > 0 && options.collect_diagnostics {
+                let reason = if key < min_pitch { DropReason::BelowMinPitch } else { DropReason::AboveMaxPitch };
+                dropped_notes.push(DroppedNote { time, channel, key, reason });
+            }
+            return;
+        }
+        note_events.push((time, channel, key, vel));
+    };
 
-    tempo_events.push((0, 120));
-    for track in &smf.tracks {
-        let mut abs_time = 0;
+    for &(start, track) in tracks {
+        let mut abs_time: u64 = start + lead_in_ticks as u64;
+        // Counts of ignored quiet notes per channel and key, so their note-offs are ignored too.
+        let mut quiet_notes = [[0u8; 128]; 16];
         for event in track {
-            abs_time += event.delta.as_int();
+            abs_time += event.delta.as_int() as u64;
+            let time: u32 = match u32::try_from(abs_time) {
+                Ok(t) => t,
+                Err(_) => return Err(Error::SongTooLong { ticks: abs_time })
+            };
 
             match event.kind {
-                TrackEventKind::Midi { message, .. } => {
-                    let (key, state) = match message {
-                        MidiMessage::NoteOn  { key, vel } => (key.as_int(), vel.as_int() > min_velocity),
-                        MidiMessage::NoteOff { key, ..  } => (key.as_int(), false),
+                TrackEventKind::Midi { channel, message } => {
+                    if channels.is_some_and(|c| !c.contains(&channel.as_int())) { continue; }
+                    let channel_index = channel.as_int() as usize;
+                    let is_drum = channel.as_int() == DRUM_CHANNEL;
+                    if is_drum && drums == DrumMode::Skip { continue; }
+                    let (key, vel) = match message {
+                        MidiMessage::NoteOn  { key, vel } if vel.as_int() == 0 => (key.as_int(), 0),
+                        MidiMessage::NoteOn  { key, vel } if !passes_gate(channel.as_int(), key.as_int(), vel.as_int()) => {
+                            let quiet = &mut quiet_notes[channel_index][key.as_int() as usize];
+                            *quiet = quiet.saturating_add(1);
+                            continue;
+                        },
+                        MidiMessage::NoteOn  { key, vel } => (key.as_int(), vel.as_int()),
+                        MidiMessage::NoteOff { key, ..  } => (key.as_int(), 0),
+                        MidiMessage::Controller { controller, value } if controller.as_int() == SUSTAIN_CONTROLLER => {
+                            pedal_events.push((time, channel.as_int(), value.as_int() >= 64));
+                            continue;
+                        },
+                        MidiMessage::PitchBend { bend } if pitch_bend_range > 0 && !is_drum => {
+                            pitch_bends[channel_index].push((time, bend.0.as_int() as u32));
+                            continue;
+                        },
                         _ => continue
                     };
-                    if key < min_pitch || key > max_pitch { continue; }
-                    note_events.push((abs_time, key, state));
-                    used_keys[key as usize] = true;
+                    let state = vel > 0;
+                    if !state && quiet_notes[channel_index][key as usize] > 0 {
+                        quiet_notes[channel_index][key as usize] -= 1;
+                        continue;
+                    }
+                    let key = match (is_drum, drums) {
+                        (true, DrumMode::Map) => match drum_to_pitch(key) {
+                            Some(p) => p,
+                            None    => continue
+                        },
+                        _ => key
+                    };
+                    push_note(time, channel.as_int(), key, vel);
                 },
                 TrackEventKind::Meta(MetaMessage::Tempo(t)) => {
-                    tempo_events.push((abs_time, t.as_int()));
+                    tempo_events.push((time, t.as_int().max(MIN_TEMPO)));
+                },
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _)) => {
+                    time_signatures.push((time, numerator, denominator));
+                },
+                TrackEventKind::Meta(MetaMessage::Marker(text)) => {
+                    match String::from_utf8_lossy(text).trim() {
+                        LOOP_START_MARKER => { loop_start.get_or_insert(time); },
+                        LOOP_END_MARKER   => { loop_end.get_or_insert(time); },
+                        _ => {}
+                    }
                 },
                 _ => {}
             }
         }
-        total_len = total_len.max(abs_time);
+        // Checked here too, because empty tracks never reach the check of events.
+        let end: u32 = u32::try_from(abs_time).map_err(|_| Error::SongTooLong { ticks: abs_time })?;
+        total_len = total_len.max(end);
     }
 
-    // Sorting events (because we collected them from different tracks and instruments)
-    note_events.sort_by_key(|e| e.0);
+    // Sorting events (because we collected them from different tracks and instruments).
+    // Note offs go before note ons at the same time, so the order doesn't depend on tracks.
+    //
+    // This order is what every stage below gets. Off and on of a key at the same time are
+    // either a retrigger of a sounding note or a zero-length note (on and off), the order
+    // doesn't tell which. Stages that follow notes have to handle events of one time as
+    // a group, as the packing loop does by counting ons before offs: retriggered note keeps
+    // sounding and zero-length note is never played.
+    note_events.sort_by_key(|e| (e.0, e.3 > 0));
     tempo_events.sort_by_key(|e| e.0);
+    time_signatures.sort_by_key(|e| e.0);
+    pitch_bends.iter_mut().for_each(|b| b.sort_by_key(|e| e.0));
+
+    let note_events = apply_sustain(note_events, pedal_events, total_len);
+    let mut note_events = match options.max_polyphony {
+        Some(max_polyphony) => limit_polyphony(note_events, max_polyphony, options.voice_stealing),
+        None => note_events
+    };
 
-    tempo_events.push((u32::MAX, 120));
+    // Clipping to time range (shifted by lead-in, which is kept) and rebasing times to its start.
+    if start_ticks > 0 || end_ticks.is_some() {
+        let clip_start = start_ticks.saturating_add(lead_in_ticks);
+        let clip_end = end_ticks.map_or(total_len, |e| e.saturating_add(lead_in_ticks)).min(total_len);
+        if clip_start >= clip_end {
+            return Err(Error::EmptyTimeRange { start: start_ticks, end: end_ticks.unwrap_or(total_len) });
+        }
+
+        // Notes sounding at the start of the range are turned on at its start.
+        // Count and last velocity of sounding notes per (channel, key).
+        let mut sounding: HashMap<(u8, u8), (u32, u8)> = HashMap::new();
+        let mut clipped = Vec::with_capacity(note_events.len());
+        for same_time in note_events.chunk_by(|a, b| a.0 == b.0) {
+            let time = same_time[0].0;
+            if time >= clip_end { break; }
+            if time >= clip_start {
+                clipped.extend(same_time.iter().map(|&(t, channel, key, vel)| (t - start_ticks, channel, key, vel)));
+                continue;
+            }
+            // Ons are counted before offs of the same time, as in the packing loop.
+            let (ons, offs): (Vec<_>, Vec<_>) = same_time.iter().partition(|e| e.3 > 0);
+            for &(_, channel, key, vel) in ons.into_iter().chain(offs) {
+                let (count, last_vel) = sounding.entry((channel, key)).or_default();
+                if vel > 0 {
+                    *count += 1;
+                    *last_vel = vel;
+                } else {
+                    *count = count.saturating_sub(1);
+                }
+            }
+        }
+        let mut carried: Vec<_> = sounding.into_iter()
+        .flat_map(|((channel, key), (count, vel))| (0..count).map(move |_| (clip_start - start_ticks, channel, key, vel)))
+        .collect();
+        carried.sort_unstable();
+        carried.append(&mut clipped);
+        note_events = carried;
+
+        tempo_events = clip_changes(tempo_events, |e| &mut e.0, clip_start, clip_end, start_ticks);
+        time_signatures = clip_changes(time_signatures, |e| &mut e.0, clip_start, clip_end, start_ticks);
+        for bends in &mut pitch_bends {
+            *bends = clip_changes(std::mem::take(bends), |e| &mut e.0, clip_start, clip_end, start_ticks);
+        }
+        loop_start = loop_start.map(|t| t.clamp(clip_start, clip_end) - start_ticks);
+        loop_end = loop_end.map(|t| t.clamp(clip_start, clip_end) - start_ticks);
+        dropped_notes.retain(|n| (clip_start..clip_end).contains(&n.time));
+        dropped_notes.iter_mut().for_each(|n| n.time -= start_ticks);
+        total_len = clip_end - start_ticks;
+    }
+
+    if tempo_events.first().is_none_or(|e| e.0 > 0) {
+        // Lead-in plays at the tempo of the song start.
+        let tempo = tempo_events.first()
+        .filter(|e| lead_in_ticks > 0 && e.0 == lead_in_ticks)
+        .map_or(DEFAULT_TEMPO, |e| e.1);
+        tempo_events.insert(0, (0, tempo));
+    }
+    for bends in pitch_bends.iter_mut().filter(|b| b.first().is_some_and(|e| e.0 > 0)) {
+        bends.insert(0, (0, PITCH_BEND_CENTER));
+    }
+
+    // Missing start loops from the beginning, missing end loops until the end of the song.
+    let loop_range = match (loop_start, loop_end) {
+        (None, None) => None,
+        (start, end) => Some((start.unwrap_or(0), end.unwrap_or(total_len).min(total_len)))
+    }.filter(|(start, end)| start < end);
+
+    let mut used_keys = [false; 128];
+    for &(_, _, key, _) in &note_events {
+        used_keys[key as usize] = true;
+    }
 
     // Creating hash map for mapping used keys to indices.
     let mut key_mapping: HashMap<u8, usize> = HashMap::new();
@@ -126,10 +897,15 @@ pub fn midi_to_data_changes(smf: Smf, min_pitch: u8, max_pitch: u8, min_velocity
     }
 
     let used_keys_count = key_mapping.len();
-    let channels_count = ((used_keys_count.checked_sub(1).unwrap_or(0)) / notes_per_value as usize) + 1;
+    if used_keys_count == 0 {
+        return Err(Error::NoPlayableNotes { min_pitch, max_pitch });
+    }
+    let channels_count = ((used_keys_count - 1) / notes_per_value as usize) + 1;
 
     // Encoding note changes into bits of values.
     let mut note_counters = vec![0u8; used_keys_count];
+    // Velocity level of the last NoteOn of every key.
+    let mut note_levels = vec![0u32; used_keys_count];
     let mut data_changes: Vec<Vec<(u32, u32)>> = vec![Vec::new(); channels_count];
 
     let mut i = 0;
@@ -144,191 +920,659 @@ pub fn midi_to_data_changes(smf: Smf, min_pitch: u8, max_pitch: u8, min_velocity
 
         let events = &note_events[i..j];
 
-        for &(_, key, state) in events {
+        // Note ons are counted before note offs of the same time, so counter isn't clamped at 0
+        // in between: retriggered note (off and on) keeps playing, zero-length note (on and
+        // off) doesn't get stuck.
+        let (ons, offs): (Vec<_>, Vec<_>) = events.iter().partition(|e| e.3 > 0);
+        for &(_, _, key, vel) in ons.into_iter().chain(offs) {
             let mapped = key_mapping[&key];
-            // !todo: needs check for overflow
-            if state == true {
-                note_counters[mapped] = note_counters[mapped]
-                .checked_add(1)
-                .unwrap_or(u8::MAX);
+            if vel > 0 {
+                note_levels[mapped] = velocity_level(vel, bits_per_note);
+                note_counters[mapped] = note_counters[mapped].saturating_add(1);
             } else {
-                note_counters[mapped] = note_counters[mapped]
-                .checked_sub(1)
-                .unwrap_or(0);
+                note_counters[mapped] = note_counters[mapped].saturating_sub(1);
             }
         }
 
-        for c in 0..channels_count {
-            let prev_data = data_changes[c].last().unwrap_or(&(0, 0)).1;
+        for (c, changes) in data_changes.iter_mut().enumerate() {
+            let prev_data = changes.last().map_or(0, |e| e.1);
             let mut data = 0;
             for bit in 0..notes_per_value {
                 let index = c * notes_per_value as usize + bit as usize;
-                if !(index < used_keys_count) || note_counters[index] < 1 { continue; }
-                data = data | 1 << bit;
+                if index >= used_keys_count || note_counters[index] < 1 { continue; }
+                data |= note_levels[index] << (bit as u32 * bits_per_note as u32);
             }
             if data != prev_data {
-                data_changes[c].push((current_time, data));
+                changes.push((current_time, data));
             }
         }
 
         i = j;
     }
 
-    Ok((ppq, data_changes, index_to_key, tempo_events, total_len))
+    Ok(EncodedNotes {
+        ppq,
+        note_changes: data_changes,
+        pitches: index_to_key,
+        tempo_changes: tempo_events,
+        time_signatures,
+        loop_range,
+        pitch_bends,
+        total_len,
+        dropped_notes
+    })
 }
 
-pub fn build_music_player(
-    note_changes: Vec<Vec<(u32, u32)>>,
-    tempo_changes: Vec<(u32, u32)>,
-    pitches: Vec<u8>,
-    notes_per_value: u8,
-    max_events_per_func: usize,
-    total_len: u32,
-    ppq: u32,
-    repeat: bool
-) -> Result<Building> {
-    // Special positions for blocks.
-    const SWITCH_POSITION   : [f32; 3] = [ 0.0 , 0.015625 ,  0.25 ];
-    const TONE_GEN_POSITION : [f32; 3] = [ 0.0 , 0.0      , -0.25 ];
-
-    // Decoder function
-    let mut decoder_func = String::new();
-    for (p, ind) in (1..=notes_per_value as usize).rev().enumerate() {
-        write!(decoder_func, "ind({})=step(0.5,(A%1/2^{})*2^{})+step(1,A);", ind, p, p)?;
-    }
-    decoder_func.push('0');
+/// Cuts notes so that at most `max_polyphony` notes sound at once. NoteOn over the limit
+/// turns off the note chosen by `policy` at its time (or isn't played, if it is the one
+/// chosen), and NoteOff of the cut note is dropped.
+///
+/// Expects note events `(time, channel, key, velocity)` sorted by time with NoteOffs first,
+/// velocity 0 being NoteOff. NoteOff of a key that isn't sounding ends NoteOn of the same
+/// key and time, a zero-length note, and both are dropped: packing wouldn't play it either.
+fn limit_polyphony(note_events: Vec<(u32, u8, u8, u8)>, max_polyphony: usize, policy: VoiceStealing) -> Vec<(u32, u8, u8, u8)> {
+    let mut result = Vec::with_capacity(note_events.len());
+    // Sounding notes (channel, key, velocity) in order they started.
+    let mut active: Vec<(u8, u8, u8)> = Vec::new();
+    // Counts of cut notes per (channel, key) still waiting for their NoteOff.
+    let mut cut: HashMap<(u8, u8), u32> = HashMap::new();
+    // Counts of NoteOffs per (channel, key) ending zero-length notes of the current time.
+    let mut zero_length: HashMap<(u8, u8), u32> = HashMap::new();
 
-    // Initializing array with blocks that are always present.
-    let mut blocks: Vec<Block> = vec![
-        Block { // 0
-            id: 129, // Math block
-            metadata: Some(Metadata {
-                type_settings: TypeSettings::MathBlock {
-                    function: {
-                        let mut f = String::new();
-                        write!(
-                            f,
-                            "s=0.0001;tempo_us=max(C*{},1);dt_sec=1/50;dt_ticks=dt_sec*{}*1000000/tempo_us;B=1-B;A*(Lval+dt_ticks/{}){}",
-                               2u32.pow(24),
-                               ppq,
-                               total_len,
-                               if repeat { "%1" } else { "" }
-                        )?;
-                        f
-                    },
-                    incoming_connections_order: Vec::new(),
-                           slots: Vec::new()
-                },
-                ..Default::default()
-            }),
-            connections: vec![2, 4],
-            ..Default::default()
-        },
-        Block { // 1: A
-            id: 9, // Switch
-            position: SWITCH_POSITION,
-            connections: vec![0],
-            ..Default::default()
-        },
-        Block { // 2 - Forces math block to update: B
-            id: 78, // OR
-            connections: vec![0],
-            name: "2".into(),
-            ..Default::default()
-        },
-        Block { // 3 - Main math block input (for tempo): C
-            id: 78, // OR
-            connections: vec![0],
-            name: "3".into(),
-            ..Default::default()
-        },
-        Block { // 4 - Main math block output: D
-            id: 78, // OR
-            name: "4".into(),
-            ..Default::default()
-        }
-    ];
+    for same_time in note_events.chunk_by(|a, b| a.0 == b.0) {
+        zero_length.clear();
 
-    // Generating funcs and creating blocks
-    for (c, channel_changes) in note_changes.into_iter().enumerate() {
-        blocks.push(Block {
-            id: 129,
-            metadata: Some(Metadata {
-                type_settings: TypeSettings::MathBlock {
-                    function: decoder_func.clone(),
-                           incoming_connections_order: Vec::new(),
-                           slots: Vec::new()
-                },
-                ..Default::default()
-            }),
-            ..Default::default()
-        });
-        let decoder_index: u16 = (blocks.len() - 1).try_into()?;
+        for &event in same_time {
+            let (time, channel, key, vel) = event;
 
-        blocks.push(Block {
-            id: 78,
-            connections: vec![decoder_index],
-            ..Default::default()
-        });
-        let decoder_input_index: u16 = (blocks.len() - 1).try_into()?;
+            if vel == 0 {
+                if let Some(count) = cut.get_mut(&(channel, key)) && *count > 0 {
+                    *count -= 1;
+                    continue;
+                }
+                match active.iter().position(|&(c, k, _)| c == channel && k == key) {
+                    Some(i) => {
+                        active.remove(i);
+                        result.push(event);
+                    },
+                    None => *zero_length.entry((channel, key)).or_default() += 1
+                }
+                continue;
+            }
 
-        for n in 0..notes_per_value {
-            let index = c * notes_per_value as usize + n as usize;
-            let &pitch = match pitches.get(index) {
-                Some(p) => p,
-                None    => continue
-            };
-            let freq = pitch_to_freq(pitch);
+            if let Some(count) = zero_length.get_mut(&(channel, key)) && *count > 0 {
+                *count -= 1;
+                continue;
+            }
 
-            blocks.push(Block {
-                id: 125,
-                position: TONE_GEN_POSITION,
-                metadata: Some(Metadata {
-                    values: vec![freq, 100.0],
-                    ..Default::default()
-                }),
-                ..Default::default()
-            });
-            let tone_gen_index: u16 = (blocks.len() - 1).try_into()?;
+            active.push((channel, key, vel));
 
-            if let Some(decoder) = blocks.get_mut(decoder_index as usize) {
-                decoder.connections.push(tone_gen_index);
+            if active.len() > max_polyphony {
+                let victim = match policy {
+                    VoiceStealing::Oldest => 0,
+                    VoiceStealing::LowestVelocity => active.iter()
+                    .enumerate()
+                    .min_by_key(|&(i, &(_, _, v))| (v, i))
+                    .map_or(0, |(i, _)| i)
+                };
+                let (c, k, _) = active.remove(victim);
+                *cut.entry((c, k)).or_default() += 1;
+                // New note is the quietest one, it isn't played at all.
+                if victim == active.len() { continue; }
+                result.push((time, c, k, 0));
             }
+            result.push(event);
         }
+    }
 
-        let functions = data_to_functions(channel_changes, max_events_per_func);
+    result
+}
+
+/// Clips sorted changes of some state to `start..end` and moves them `offset` earlier.
+/// The last change before `start` is moved to `start`, so the state is kept from there.
+fn clip_changes<E>(changes: Vec<E>, time: impl Fn(&mut E) -> &mut u32, start: u32, end: u32, offset: u32) -> Vec<E> {
+    let mut clipped: Vec<E> = Vec::with_capacity(changes.len());
+    for mut change in changes {
+        let t = time(&mut change);
+        if *t >= end { break; }
+        if *t < start {
+            *t = start;
+            clipped.clear();
+        }
+        *t -= offset;
+        clipped.push(change);
+    }
+    clipped
+}
+
+/// Rebuilds MIDI from data returned by [`encode_notes`], emitting NoteOn/NoteOff
+/// for every note whose level changes in packed note values.
+///
+/// Packing loses channels and overlaps of notes with the same pitch, so the result has one
+/// track with all notes on channel 0. With one bit per note velocities are lost too and notes
+/// have velocity 100. With more bits notes get the highest velocity of their level, which
+/// encodes to the same level, and a note changing level is retriggered. Useful for checking
+/// encoder output against the source MIDI. Gaps between events longer than MIDI delta time
+/// can hold return [`Error::GapTooLong`].
+pub fn data_changes_to_midi(
+    ppq: u32,
+    note_changes: &[Vec<(u32, u32)>],
+    pitches: &[u8],
+    tempo_changes: &[(u32, u32)],
+    notes_per_value: u8,
+    bits_per_note: u8
+) -> Result<Smf<'static>> {
+    let ppq: u16 = ppq.try_into()?;
+    value_bits(&PlayerOptions::default().notes_per_value(notes_per_value).bits_per_note(bits_per_note))?;
+    let bits_per_note = bits_per_note.max(1) as u32;
+    let max_level = (1u32 << bits_per_note) - 1;
+
+    // (time, is note on, event)
+    let mut events: Vec<(u32, bool, TrackEventKind<'static>)> = Vec::new();
+
+    for &(time, tempo) in tempo_changes {
+        events.push((time, false, TrackEventKind::Meta(MetaMessage::Tempo(tempo.min(0xFF_FFFF).into()))));
+    }
+
+    for (c, channel_changes) in note_changes.iter().enumerate() {
+        let mut prev_data = 0;
+        for &(time, data) in channel_changes {
+            for n in 0..notes_per_value {
+                let shift = n as u32 * bits_per_note;
+                let level = data >> shift & max_level;
+                if level == prev_data >> shift & max_level { continue; }
+                let Some(&key) = pitches.get(c * notes_per_value as usize + n as usize) else { continue };
+
+                if prev_data >> shift & max_level > 0 {
+                    let message = MidiMessage::NoteOff { key: key.into(), vel: 0.into() };
+                    events.push((time, false, TrackEventKind::Midi { channel: 0.into(), message }));
+                }
+                if level > 0 {
+                    let vel = if bits_per_note == 1 { 100 } else { (level * 127).div_ceil(max_level).min(127) as u8 };
+                    let message = MidiMessage::NoteOn { key: key.into(), vel: vel.into() };
+                    events.push((time, true, TrackEventKind::Midi { channel: 0.into(), message }));
+                }
+            }
+            prev_data = data;
+        }
+    }
+
+    // Note offs go before note ons at the same time, so repeated notes are retriggered.
+    events.sort_by_key(|e| (e.0, e.1));
+
+    let mut track = Vec::with_capacity(events.len() + 1);
+    let mut last_time = 0;
+    for (time, _, kind) in events {
+        let gap = time - last_time;
+        let delta = u28::try_from(gap).ok_or(Error::GapTooLong { ticks: gap })?;
+        track.push(TrackEvent { delta, kind });
+        last_time = time;
+    }
+    track.push(TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+
+    Ok(Smf {
+        header: Header::new(Format::SingleTrack, Timing::Metrical(ppq.into())),
+        tracks: vec![track]
+    })
+}
+
+/// Maximal volume of tone generator.
+pub const MAX_VOLUME: f32 = 100.0;
+
+/// Amount of bits tempo values are scaled down by in data blocks.
+const TEMPO_BITS: u32 = 24;
+
+/// Pitch bend value of no bend, the middle of 14-bit MIDI pitch bend range.
+pub const PITCH_BEND_CENTER: u32 = 0x2000;
+
+/// Amount of bits pitch bend values are scaled down by in data blocks.
+const PITCH_BEND_BITS: u32 = 14;
+
+/// Number of bits of packed note values, checking that they fit in math block.
+fn value_bits(options: &PlayerOptions) -> Result<u32> {
+    let &PlayerOptions { notes_per_value, bits_per_note, .. } = options;
+    if notes_per_value > MAX_NOTES_PER_VALUE {
+        return Err(Error::NotesPerValueTooLarge { notes_per_value });
+    }
+    let bits = notes_per_value as u32 * bits_per_note as u32;
+    if bits > MAX_NOTES_PER_VALUE as u32 {
+        return Err(Error::ValueBitsTooLarge { notes_per_value, bits_per_note });
+    }
+    Ok(bits)
+}
+
+/// Velocity level (1 to `2^bits_per_note - 1`) of NoteOn velocity (1-127).
+fn velocity_level(vel: u8, bits_per_note: u8) -> u32 {
+    let levels = (1u32 << bits_per_note) - 1;
+    1 + (vel.max(1) as u32 - 1) * levels / 127
+}
+
+/// Wraps function from [`data_to_functions`] into data block function, which outputs
+/// data scaled down by `2^bits`.
+fn data_block_function(total_len: u32, bits: u32, f: &str) -> String {
+    format!("x=A*{};n={};n/{}", total_len, f, 2u32.pow(bits))
+}
+
+/// Characters left for [`data_to_functions`] output in data block function.
+fn data_function_budget(max_chars_per_func: Option<usize>, total_len: u32, bits: u32) -> Option<usize> {
+    max_chars_per_func.map(|m| m.saturating_sub(data_block_function(total_len, bits, "").len()))
+}
+
+/// Function of measure marker block, which outputs 1 during the first beat of every
+/// measure and 0 otherwise.
+///
+/// Measure boundaries depend only on PPQ and time signatures (not on tempo, which only
+/// changes how fast ticks go). With `x` being current time in ticks, every time signature
+/// `n/2^d` starting at tick `t0` and lasting until `t1` adds a term
+/// `(step(t0,x)-step(t1,x))*(1-step(b,(x-t0)%(n*b)))`, where `b = 4*ppq/2^d` is length
+/// of a beat in ticks. Songs without time signature at tick 0 start in 4/4.
+///
+/// Time signatures of a song clipped by `start_ticks` start at the clip start (see
+/// [`clip_changes`]), so measures are counted from there: a clip that doesn't start on a
+/// bar line shifts all measures of the clip.
+fn measure_marker_function(time_signatures: &[(u32, u8, u8)], ppq: u32, total_len: u32) -> Result<String> {
+    let mut segments: Vec<(u32, u8, u8)> = Vec::with_capacity(time_signatures.len() + 1);
+    if time_signatures.first().is_none_or(|s| s.0 > 0) {
+        segments.push((0, 4, 2));
+    }
+    for &signature in time_signatures {
+        if signature.1 == 0 || signature.0 >= total_len { continue; }
+        // Later signature at the same tick replaces the earlier one.
+        match segments.last_mut() {
+            Some(last) if last.0 == signature.0 => *last = signature,
+            _ => segments.push(signature)
+        }
+    }
+
+    let mut f = format!("x=A*{};", total_len);
+    for (i, &(start, numerator, denominator)) in segments.iter().enumerate() {
+        let beat = ppq as f64 * 4.0 / 2f64.powi(denominator as i32);
+        let measure = beat * numerator as f64;
+        let end = segments.get(i + 1).map(|s| s.0);
+
+        if i > 0 { f.push('+'); }
+        match (start, end) {
+            (0, None)        => {},
+            (0, Some(end))   => write!(f, "(1-step({},x))*", end)?,
+            (start, None)    => write!(f, "step({},x)*", start)?,
+            (start, Some(end)) => write!(f, "(step({},x)-step({},x))*", start, end)?
+        }
+        if start == 0 {
+            write!(f, "(1-step({},x%{}))", beat, measure)?;
+        } else {
+            write!(f, "(1-step({},(x-{})%{}))", beat, start, measure)?;
+        }
+    }
+
+    Ok(f)
+}
+
+/// Function of pitch block of a bent group, which outputs frequency `ind(k)` of the tone
+/// generator of `pitches[k-1]`.
+///
+/// Input `A` is the 14-bit pitch bend scaled down by `2^14` (see data blocks), so it is 0.5
+/// without bend and `2*A-1` is bend from -1 to almost 1. Every frequency is multiplied by
+/// `2^(range*(2*A-1)/12)`, the ratio of `range` semitones at full bend.
+fn pitch_block_function(pitches: &[u8], a4_hz: f32, range: u8) -> Result<String> {
+    let mut f = format!("b=2^({}*(2*A-1)/12);", range);
+    for (k, &pitch) in pitches.iter().enumerate() {
+        write!(f, "ind({})={}*b;", k + 1, pitch_to_freq(pitch, a4_hz))?;
+    }
+    f.push('0');
+
+    Ok(f)
+}
+
+/// Calls [`data_to_functions`] for every channel, in parallel with `rayon` feature.
+/// Functions are returned in channel order either way.
+fn channels_to_functions(note_changes: Vec<Vec<(u32, u32)>>, max_events_per_func: usize, max_chars_per_func: Option<usize>) -> Vec<Vec<String>> {
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        note_changes
+        .into_par_iter()
+        .map(|changes| data_to_functions(changes, max_events_per_func, max_chars_per_func))
+        .collect()
+    }
+    #[cfg(not(feature = "rayon"))]
+    {
+        note_changes
+        .into_iter()
+        .map(|changes| data_to_functions(changes, max_events_per_func, max_chars_per_func))
+        .collect()
+    }
+}
+
+/// Channels of packed notes sharing one pitch mapping (see [`EncodedNotes`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoteGroup {
+    /// Name of OR gate passing time from main output to the group's data blocks.
+    /// Groups without name have no OR gate and are fed by main output directly.
+    pub name: Option<String>,
+    /// Changes of packed note values, one list per value.
+    pub note_changes: Vec<Vec<(u32, u32)>>,
+    /// Pitch of every bit index.
+    pub pitches: Vec<u8>,
+    /// Side tone generators of the group are placed on.
+    pub pan: Pan,
+    /// Pitch bend changes of all notes of the group (see [`EncodedNotes::pitch_bends`]),
+    /// empty if they aren't bent.
+    pub pitch_bends: Vec<(u32, u32)>
+}
+
+/// Builds one music player building with all `groups`.
+///
+/// Song data is the same as in [`EncodedNotes`], only player options of `options` are
+/// used. With `emit_beat_marker`, a math block named `measure` is fed by main output and
+/// outputs 1 during the first beat of every measure (its output is left unconnected).
+///
+/// A group with `pitch_bends` gets a pitch math block fed by its bend data blocks, which
+/// outputs the frequency of every tone generator of the group, bent by up to
+/// `pitch_bend_range` semitones at full bend. Its outputs are connected
+/// to the frequency inputs of the tone generators, so bends are as smooth as their MIDI events. Tone generators of bent groups are never shared by `dedupe_tones`.
+pub fn build_music_player(
+    groups: Vec<NoteGroup>,
+    tempo_changes: Vec<(u32, u32)>,
+    time_signatures: &[(u32, u8, u8)],
+    loop_range: Option<(u32, u32)>,
+    ppq: u32,
+    total_len: u32,
+    options: &PlayerOptions
+) -> Result<Building> {
+    let &PlayerOptions {
+        notes_per_value,
+        repeat,
+        max_events_per_func,
+        max_chars_per_func,
+        a4_hz,
+        master_volume,
+        emit_beat_marker,
+        fixed_tempo_bpm,
+        dedupe_tones,
+        palette,
+        pitch_bend_range,
+        ..
+    } = options;
+    let measure_marker = emit_beat_marker.then_some(time_signatures);
+
+    let value_bits = value_bits(options)?;
+    palette.validate()?;
+
+    // Special positions for blocks.
+    const SWITCH_POSITION   : [f32; 3] = [ 0.0 , 0.015625 ,  0.25 ];
+    const TONE_GEN_POSITION : [f32; 3] = [ 0.0 , 0.0      , -0.25 ];
+
+    let decoder_func = build_layered_decoder_function(notes_per_value as usize, options.bits_per_note as usize);
+
+    // Generating funcs up front, so the number of blocks is known before creating them.
+    let mut groups_functions = Vec::with_capacity(groups.len());
+    let mut groups_pitches = Vec::with_capacity(groups.len());
+    let mut groups_names = Vec::with_capacity(groups.len());
+    let mut groups_pans = Vec::with_capacity(groups.len());
+    let mut groups_bend_functions = Vec::with_capacity(groups.len());
+    for group in groups {
+        groups_functions.push(channels_to_functions(
+            group.note_changes,
+            max_events_per_func,
+            data_function_budget(max_chars_per_func, total_len, value_bits)
+        ));
+        groups_pitches.push(group.pitches);
+        groups_names.push(group.name);
+        groups_pans.push(group.pan);
+        groups_bend_functions.push(data_to_functions(
+            group.pitch_bends,
+            max_events_per_func,
+            data_function_budget(max_chars_per_func, total_len, PITCH_BEND_BITS)
+        ));
+    }
+
+    // Fixed tempo is baked into main math block instead of tempo data blocks.
+    let tempo_functions = if fixed_tempo_bpm.is_some() {
+        Vec::new()
+    } else {
+        data_to_functions(
+            tempo_changes,
+            max_events_per_func,
+            data_function_budget(max_chars_per_func, total_len, TEMPO_BITS)
+        )
+    };
+
+    // Upper bound of block count: 5 fixed blocks, OR gate of every named group, pitch block,
+    // its input and bend data blocks of every bent group, decoder, its input and tone
+    // generators of every channel, data blocks, tempo blocks and marker.
+    let capacity = 5
+        + groups_names.iter().filter(|n| n.is_some()).count()
+        + groups_bend_functions.iter().filter(|f| !f.is_empty()).map(|f| 2 + f.len()).sum::<usize>()
+        + groups_functions.iter().flatten().map(|f| 2 + notes_per_value as usize + f.len()).sum::<usize>()
+        + tempo_functions.len()
+        + measure_marker.is_some() as usize;
+
+    // Initializing array with blocks that are always present.
+    let mut blocks: Vec<Block> = vec![
+        Block { // 0
+            id: palette.math,
+            metadata: Some(Metadata {
+                type_settings: TypeSettings::MathBlock {
+                    function: {
+                        let mut f = String::new();
+                        match fixed_tempo_bpm {
+                            Some(bpm) => write!(f, "s=0.0001;tempo_us={};", (60_000_000.0 / bpm).max(1.0))?,
+                            None => write!(f, "s=0.0001;tempo_us=max(C*{},1);", 2u32.pow(TEMPO_BITS))?
+                        }
+                        write!(f, "dt_sec=1/50;dt_ticks=dt_sec*{}*1000000/tempo_us;B=1-B;", ppq)?;
+                        match (repeat, loop_range) {
+                            // Jumping back by the length of the section once its end is passed.
+                            (true, Some((start, end))) => write!(
+                                f,
+                                "p=Lval+dt_ticks/{};A*(p-step({}/{},p)*{}/{})",
+                                total_len,
+                                end, total_len,
+                                end - start, total_len
+                            )?,
+                            (true, None) => write!(f, "A*(Lval+dt_ticks/{})%1", total_len)?,
+                            (false, _) => write!(f, "A*(Lval+dt_ticks/{})", total_len)?
+                        }
+                        f
+                    },
+                    incoming_connections_order: Vec::new(),
+                           slots: Vec::new()
+                },
+                ..Default::default()
+            }),
+            connections: vec![2, 4],
+            ..Default::default()
+        },
+        Block { // 1: A
+            id: palette.switch,
+            position: SWITCH_POSITION,
+            connections: vec![0],
+            ..Default::default()
+        },
+        Block { // 2 - Forces math block to update: B
+            id: palette.or,
+            connections: vec![0],
+            name: "2".into(),
+            ..Default::default()
+        },
+        Block { // 3 - Main math block input (for tempo): C
+            id: palette.or,
+            connections: vec![0],
+            name: "3".into(),
+            ..Default::default()
+        },
+        Block { // 4 - Main math block output: D
+            id: palette.or,
+            name: "4".into(),
+            ..Default::default()
+        }
+    ];
+    blocks.reserve_exact(capacity - blocks.len());
+
+    // Tone generator of every pitch and side, shared by decoders of all groups with `dedupe_tones`.
+    let mut tone_gens: HashMap<(u8, Pan), u16> = HashMap::new();
+
+    // Creating blocks
+    for ((((name, pitches), pan), group_functions), bend_functions) in groups_names.into_iter()
+    .zip(groups_pitches)
+    .zip(groups_pans)
+    .zip(groups_functions)
+    .zip(groups_bend_functions)
+    {
+        // Named group gets OR gate between main output and its data blocks.
+        let group_input_index: u16 = match name {
+            Some(name) => {
+                blocks.push(Block {
+                    id: palette.or,
+                    name,
+                    ..Default::default()
+                });
+                let index: u16 = (blocks.len() - 1).try_into()?;
+
+                if let Some(main_output) = blocks.get_mut(4) {
+                    main_output.connections.push(index);
+                }
+                index
+            },
+            None => 4
+        };
+
+        // Pitch block of a bent group, its outputs are connected to tone generators as they are created.
+        let pitch_block_index: Option<u16> = if bend_functions.is_empty() {
+            None
+        } else {
+            blocks.push(Block {
+                id: palette.math,
+                metadata: Some(Metadata {
+                    type_settings: TypeSettings::MathBlock {
+                        function: pitch_block_function(&pitches, a4_hz, pitch_bend_range)?,
+                        incoming_connections_order: Vec::new(),
+                        slots: Vec::new()
+                    },
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+            let pitch_block_index: u16 = (blocks.len() - 1).try_into()?;
 
-        for f in functions {
             blocks.push(Block {
-                id: 129,
+                id: palette.or,
+                connections: vec![pitch_block_index],
+                ..Default::default()
+            });
+            let pitch_input_index: u16 = (blocks.len() - 1).try_into()?;
+
+            for f in bend_functions {
+                blocks.push(Block {
+                    id: palette.math,
+                    metadata: Some(Metadata {
+                        type_settings: TypeSettings::MathBlock {
+                            function: data_block_function(total_len, PITCH_BEND_BITS, &f),
+                            incoming_connections_order: Vec::new(),
+                            slots: Vec::new()
+                        },
+                        ..Default::default()
+                    }),
+                    connections: vec![pitch_input_index],
+                    ..Default::default()
+                });
+                let data_block_index: u16 = (blocks.len() - 1).try_into()?;
+
+                if let Some(group_input) = blocks.get_mut(group_input_index as usize) {
+                    group_input.connections.push(data_block_index);
+                }
+            }
+            Some(pitch_block_index)
+        };
+
+        for (c, functions) in group_functions.into_iter().enumerate() {
+            blocks.push(Block {
+                id: palette.math,
                 metadata: Some(Metadata {
                     type_settings: TypeSettings::MathBlock {
-                        function: format!("x=A*{};n={};n/{}", total_len, f, 2u32.pow(notes_per_value as u32)),
+                        function: decoder_func.clone(),
                                incoming_connections_order: Vec::new(),
                                slots: Vec::new()
                     },
                     ..Default::default()
                 }),
-                connections: vec![decoder_input_index],
                 ..Default::default()
             });
+            let decoder_index: u16 = (blocks.len() - 1).try_into()?;
 
-            let data_block_index: u16 = (blocks.len() - 1).try_into()?;
+            blocks.push(Block {
+                id: palette.or,
+                connections: vec![decoder_index],
+                ..Default::default()
+            });
+            let decoder_input_index: u16 = (blocks.len() - 1).try_into()?;
+
+            for n in 0..notes_per_value {
+                let index = c * notes_per_value as usize + n as usize;
+                let &pitch = match pitches.get(index) {
+                    Some(p) => p,
+                    None    => continue
+                };
+                let tone_gen_index: u16 = match tone_gens.get(&(pitch, pan)) {
+                    Some(&index) if dedupe_tones && pitch_block_index.is_none() => index,
+                    _ => {
+                        let freq = pitch_to_freq(pitch, a4_hz);
+
+                        blocks.push(Block {
+                            id: palette.tone_generator,
+                            position: [TONE_GEN_POSITION[0] + pan.offset(), TONE_GEN_POSITION[1], TONE_GEN_POSITION[2]],
+                            metadata: Some(Metadata {
+                                values: vec![freq, master_volume.clamp(0.0, MAX_VOLUME)],
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        });
+                        let index: u16 = (blocks.len() - 1).try_into()?;
+                        if pitch_block_index.is_none() {
+                            tone_gens.insert((pitch, pan), index);
+                        }
+                        index
+                    }
+                };
+
+                if let Some(decoder) = blocks.get_mut(decoder_index as usize) {
+                    decoder.connections.push(tone_gen_index);
+                }
+                if let Some(pitch_block) = pitch_block_index.and_then(|i| blocks.get_mut(i as usize)) {
+                    pitch_block.connections.push(tone_gen_index);
+                }
+            }
+
+            for f in functions {
+                blocks.push(Block {
+                    id: palette.math,
+                    metadata: Some(Metadata {
+                        type_settings: TypeSettings::MathBlock {
+                            function: data_block_function(total_len, value_bits, &f),
+                                   incoming_connections_order: Vec::new(),
+                                   slots: Vec::new()
+                        },
+                        ..Default::default()
+                    }),
+                    connections: vec![decoder_input_index],
+                    ..Default::default()
+                });
+
+                let data_block_index: u16 = (blocks.len() - 1).try_into()?;
 
-            if let Some(main_output) = blocks.get_mut(4) {
-                main_output.connections.push(data_block_index);
+                if let Some(group_input) = blocks.get_mut(group_input_index as usize) {
+                    group_input.connections.push(data_block_index);
+                }
             }
         }
     }
 
     // Generating blocks with tempo data
-    let functions = data_to_functions(tempo_changes, max_events_per_func);
-    for f in functions {
+    for f in tempo_functions {
         blocks.push(Block {
-            id: 129,
+            id: palette.math,
             metadata: Some(Metadata {
                 type_settings: TypeSettings::MathBlock {
-                    function: format!("x=A*{};n={};n/{}", total_len, f, 2u32.pow(24)),
+                    function: data_block_function(total_len, TEMPO_BITS, &f),
                            incoming_connections_order: Vec::new(),
                            slots: Vec::new()
                 },
@@ -345,13 +1589,78 @@ pub fn build_music_player(
         }
     }
 
-    Ok(Building {
+    // Measure marker, driven by main output. Its output is left for the user to connect.
+    if let Some(time_signatures) = measure_marker {
+        blocks.push(Block {
+            id: palette.math,
+            name: "measure".into(),
+            metadata: Some(Metadata {
+                type_settings: TypeSettings::MathBlock {
+                    function: measure_marker_function(time_signatures, ppq, total_len)?,
+                    incoming_connections_order: Vec::new(),
+                    slots: Vec::new()
+                },
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let marker_index: u16 = (blocks.len() - 1).try_into()?;
+
+        if let Some(main_output) = blocks.get_mut(4) {
+            main_output.connections.push(marker_index);
+        }
+    }
+
+    debug_assert!(blocks.len() <= capacity, "{} blocks exceed estimate of {}", blocks.len(), capacity);
+
+    let building = Building {
         roots: vec![Root::default()],
         blocks
-    })
+    };
+    validate(&building)?;
+
+    Ok(building)
+}
+
+/// Checks that every connection of every block points to existing block.
+pub fn validate(building: &Building) -> Result<()> {
+    for (i, block) in building.blocks.iter().enumerate() {
+        if let Some(&target) = block.connections.iter().find(|&&c| c as usize >= building.blocks.len()) {
+            return Err(Error::DanglingConnection { block: i, target });
+        }
+    }
+    Ok(())
+}
+
+/// Summary of a generated music player.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConversionStats {
+    /// Number of blocks in the building.
+    pub block_count: usize,
+    /// Number of values (decoders) notes are packed into.
+    pub channel_count: usize,
+    /// Number of distinct pitches played.
+    pub used_note_count: usize,
+    /// Number of tempo changes in MIDI.
+    pub tempo_change_count: usize,
+    /// Length of the song in ticks.
+    pub total_len: u32,
+    /// Number of notes dropped for being out of pitch range, only counted when
+    /// [`PlayerOptions::collect_diagnostics`] is set.
+    pub dropped_note_count: usize
 }
 
-pub fn generate_music_player(
+/// Converts MIDI into a music player building.
+pub fn generate_music_player(smf: Smf, options: &PlayerOptions) -> Result<Building> {
+    let (building, _) = generate_music_player_with_stats(smf, options)?;
+
+    Ok(building)
+}
+
+/// [`generate_music_player`] with positional options, as it was before [`PlayerOptions`].
+#[deprecated(note = "use `generate_music_player` with `PlayerOptions`")]
+pub fn generate_music_player_positional(
     smf: Smf,
     notes_per_value: u8,
     min_pitch: u8,
@@ -360,22 +1669,1023 @@ pub fn generate_music_player(
     repeat: bool,
     max_events_per_func: usize
 ) -> Result<Building> {
-    let (ppq, note_changes, pitches, tempo_changes, total_len) = midi_to_data_changes(
+    generate_music_player(
         smf,
-        min_pitch,
-        max_pitch,
-        min_velocity,
-        notes_per_value
-    )?;
+        &PlayerOptions::default()
+        .notes_per_value(notes_per_value)
+        .min_pitch(min_pitch)
+        .max_pitch(max_pitch)
+        .min_velocity(min_velocity)
+        .repeat(repeat)
+        .max_events_per_func(max_events_per_func)
+    )
+}
+
+/// Same as [`generate_music_player`], but also returns [`ConversionStats`].
+/// `max_blocks_per_building` of `options` is ignored, the player is never split.
+pub fn generate_music_player_with_stats(smf: Smf, options: &PlayerOptions) -> Result<(Building, ConversionStats)> {
+    let options = options.clone().max_blocks_per_building(None);
+    let (mut buildings, stats) = generate_music_players_with_stats(smf, &options)?;
+
+    Ok((buildings.remove(0), stats))
+}
+
+/// Same as [`generate_music_player`], but splits the player into several buildings
+/// when it has more than `max_blocks_per_building` blocks.
+///
+/// Every building is a self-contained player with the whole tempo map and a subset of
+/// channels (decoders with their tone generators and data), so all of them have to be
+/// started together. A channel is never split, so a building with a single channel may
+/// still exceed the limit.
+pub fn generate_music_players(smf: Smf, options: &PlayerOptions) -> Result<Vec<Building>> {
+    let (buildings, _) = generate_music_players_with_stats(smf, options)?;
 
-    Ok(build_music_player(
-        note_changes,
+    Ok(buildings)
+}
+
+/// Same as [`generate_music_players`], but also returns [`ConversionStats`] summed over all buildings.
+pub fn generate_music_players_with_stats(smf: Smf, options: &PlayerOptions) -> Result<(Vec<Building>, ConversionStats)> {
+    let (encoded, groups) = encode_groups(&smf, options)?;
+    let EncodedNotes { ppq, tempo_changes, time_signatures, loop_range, total_len, dropped_notes, .. } = encoded;
+
+    let (buildings, mut stats) = build_music_players(
+        groups,
         tempo_changes,
-        pitches,
+        &time_signatures,
+        loop_range,
+        ppq,
+        total_len,
+        options
+    )?;
+
+    stats.tempo_change_count = smf.tracks.iter()
+    .flatten()
+    .filter(|e| matches!(e.kind, TrackEventKind::Meta(MetaMessage::Tempo(_))))
+    .count();
+    stats.dropped_note_count = dropped_notes.len();
+
+    Ok((buildings, stats))
+}
+
+/// Estimates total block count of [`generate_music_players`] without building blocks,
+/// only notes are encoded and math functions are split.
+///
+/// The estimate is exact, except with `dedupe_tones`, where shared tone generators are
+/// counted once per group, so it is an upper bound.
+pub fn estimate_block_count(smf: &Smf, options: &PlayerOptions) -> Result<usize> {
+    let (encoded, mut groups) = encode_groups(smf, options)?;
+
+    silence_at_end(&mut groups, encoded.total_len, options);
+    let plan = plan_buildings(&groups, &encoded.tempo_changes, encoded.total_len, options);
+
+    Ok(plan.iter().map(|(_, cost)| cost).sum())
+}
+
+/// Encodes MIDI into note groups: one unnamed group, or one group per track with
+/// `group_by_track`, every one split by side with `stereo`. Every MIDI channel with pitch
+/// bends is split into a group of its own, named `channel <n>`, which gets its bends.
+/// Notes of returned [`EncodedNotes`] are moved into groups in the first case.
+fn encode_groups(smf: &Smf, options: &PlayerOptions) -> Result<(EncodedNotes, Vec<NoteGroup>)> {
+    let mut encoded = encode_notes(smf, options)?;
+    let bent_channels: Vec<u8> = (0..16).filter(|&c| !encoded.pitch_bends[c as usize].is_empty()).collect();
+
+    if !options.group_by_track && !options.stereo && bent_channels.is_empty() {
+        let group = NoteGroup {
+            name: None,
+            note_changes: std::mem::take(&mut encoded.note_changes),
+            pitches: std::mem::take(&mut encoded.pitches),
+            pan: Pan::Center,
+            pitch_bends: Vec::new()
+        };
+        return Ok((encoded, vec![group]));
+    }
+
+    // Encoding every track (or all of them) on its own, tempo and length are taken from the whole MIDI.
+    // Length before clipping, so tracks ending before the time range are clipped to it too.
+    let song_len = encoded.total_len + options.start_ticks;
+    let offsets = track_offsets(smf);
+    let all_tracks: Vec<(u64, &[TrackEvent])> = offsets.iter().copied().zip(smf.tracks.iter().map(|t| &t[..])).collect();
+    let track_sets: Vec<(Option<String>, &[_])> = if options.group_by_track {
+        smf.tracks.iter().enumerate().map(|(i, track)| {
+            (Some(track_name(track).unwrap_or_else(|| format!("Track {}", i))), &all_tracks[i..i + 1])
+        }).collect()
+    } else {
+        vec![(None, &all_tracks[..])]
+    };
+
+    // Every side is encoded from channels panned to it, without bent channels,
+    // and every bent channel on its own.
+    let pans = if options.stereo { channel_pans(smf) } else { [Pan::Center; 16] };
+    let sides: &[Pan] = if options.stereo { &[Pan::Center, Pan::Left, Pan::Right] } else { &[Pan::Center] };
+    let parts: Vec<(Pan, Option<u8>, PlayerOptions)> = sides.iter().flat_map(|&side| {
+        let channels_of = |bent: Option<u8>| (0..16)
+        .filter(|&c| pans[c as usize] == side)
+        .filter(|c| options.channels.as_ref().is_none_or(|selected| selected.contains(c)))
+        .filter(|c| bent.map_or(!bent_channels.contains(c), |b| *c == b))
+        .collect();
+        std::iter::once(None)
+        .chain(bent_channels.iter().copied().filter(|&c| pans[c as usize] == side).map(Some))
+        .map(|bent| (side, bent, options.clone().channels(Some(channels_of(bent)))))
+        .collect::<Vec<_>>()
+    }).collect();
+
+    let mut groups = Vec::new();
+    for (name, tracks) in track_sets {
+        for (side, bent, part_options) in &parts {
+            let part_encoded = match encode_tracks(smf.header.timing, tracks, song_len, part_options) {
+                Ok(e) => e,
+                Err(Error::NoPlayableNotes { .. }) => continue,
+                Err(e) => return Err(e)
+            };
+            let name = match (&name, side) {
+                (name, Pan::Center) => name.clone(),
+                (None, Pan::Left) => Some("left".into()),
+                (None, Pan::Right) => Some("right".into()),
+                (Some(name), Pan::Left) => Some(block_name(format!("{} L", name).as_bytes())),
+                (Some(name), Pan::Right) => Some(block_name(format!("{} R", name).as_bytes()))
+            };
+            let name = match (name, bent) {
+                (name, None) => name,
+                (None, Some(channel)) => Some(format!("channel {}", channel)),
+                (Some(name), Some(channel)) => Some(block_name(format!("{} channel {}", name, channel).as_bytes()))
+            };
+            groups.push(NoteGroup {
+                name,
+                note_changes: part_encoded.note_changes,
+                pitches: part_encoded.pitches,
+                pan: *side,
+                pitch_bends: bent.map_or_else(Vec::new, |c| encoded.pitch_bends[c as usize].clone())
+            });
+        }
+    }
+
+    Ok((encoded, groups))
+}
+
+/// Side of every MIDI channel, from its first pan event. Channels without pan are centered.
+fn channel_pans(smf: &Smf) -> [Pan; 16] {
+    let mut first_pans: [Option<(u64, u8)>; 16] = [None; 16];
+    for (track, start) in smf.tracks.iter().zip(track_offsets(smf)) {
+        let mut time = start;
+        for event in track {
+            time += event.delta.as_int() as u64;
+            if let TrackEventKind::Midi { channel, message: MidiMessage::Controller { controller, value } } = event.kind
+                && controller.as_int() == PAN_CONTROLLER
+            {
+                let first = &mut first_pans[channel.as_int() as usize];
+                if first.is_none_or(|(t, _)| time < t) {
+                    *first = Some((time, value.as_int()));
+                }
+            }
+        }
+    }
+    first_pans.map(|pan| pan.map_or(Pan::Center, |(_, value)| Pan::from_midi(value)))
+}
+
+/// Builds music players from already encoded notes, without MIDI parsing. This is the
+/// second half of [`generate_music_players_with_stats`], for callers with their own encoder.
+///
+/// `groups` hold packed notes (see [`EncodedNotes`] for the format), other song data is
+/// the same as in [`EncodedNotes`], only player options of `options` are used.
+/// `tempo_change_count` of returned stats is the length of `tempo_changes`, `dropped_note_count` is 0.
+pub fn build_music_players(
+    mut groups: Vec<NoteGroup>,
+    tempo_changes: Vec<(u32, u32)>,
+    time_signatures: &[(u32, u8, u8)],
+    loop_range: Option<(u32, u32)>,
+    ppq: u32,
+    total_len: u32,
+    options: &PlayerOptions
+) -> Result<(Vec<Building>, ConversionStats)> {
+    let notes_per_value = options.notes_per_value as usize;
+
+    silence_at_end(&mut groups, total_len, options);
+
+    let channel_count = groups.iter().map(|g| g.note_changes.len()).sum();
+    let used_note_count = groups.iter().map(|g| g.pitches.len()).sum();
+    let tempo_change_count = tempo_changes.len();
+
+    let plan = plan_buildings(&groups, &tempo_changes, total_len, options);
+    let mut buildings = Vec::with_capacity(plan.len());
+
+    for (parts, _) in plan {
+        let building_groups = parts.into_iter().map(|(g, channels)| {
+            let group = &groups[g];
+            let first_pitch = (channels.start * notes_per_value).min(group.pitches.len());
+            let last_pitch = (channels.end * notes_per_value).min(group.pitches.len());
+            NoteGroup {
+                name: group.name.clone(),
+                note_changes: group.note_changes[channels].to_vec(),
+                pitches: group.pitches[first_pitch..last_pitch].to_vec(),
+                pan: group.pan,
+                pitch_bends: group.pitch_bends.clone()
+            }
+        }).collect();
+
+        buildings.push(build_music_player(
+            building_groups,
+            tempo_changes.clone(),
+            time_signatures,
+            loop_range,
+            ppq,
+            total_len,
+            options
+        )?);
+    }
+
+    let stats = ConversionStats {
+        block_count: buildings.iter().map(|b| b.blocks.len()).sum(),
+        channel_count,
+        used_note_count,
+        tempo_change_count,
+        total_len,
+        dropped_note_count: 0
+    };
+
+    Ok((buildings, stats))
+}
+
+/// Silences notes still playing at the end (e.g. without NoteOff), unless the last state
+/// should be held. Repeating player starts over at the end anyway.
+fn silence_at_end(groups: &mut [NoteGroup], total_len: u32, options: &PlayerOptions) {
+    if options.repeat || options.hold_last { return; }
+
+    for changes in groups.iter_mut().flat_map(|g| g.note_changes.iter_mut()) {
+        if changes.last().is_some_and(|&(time, data)| data != 0 && time < total_len) {
+            changes.push((total_len, 0));
+        }
+    }
+}
+
+/// Splits channels between buildings so every building stays within the block limit.
+/// Returns parts of groups (group index, channels) and block count of every building.
+#[allow(clippy::type_complexity)]
+fn plan_buildings(
+    groups: &[NoteGroup],
+    tempo_changes: &[(u32, u32)],
+    total_len: u32,
+    options: &PlayerOptions
+) -> Vec<(Vec<(usize, std::ops::Range<usize>)>, usize)> {
+    let &PlayerOptions {
         notes_per_value,
         max_events_per_func,
-        total_len,
-        ppq,
-        repeat
-    )?)
+        max_chars_per_func,
+        emit_beat_marker,
+        fixed_tempo_bpm,
+        max_blocks_per_building,
+        ..
+    } = options;
+
+    let note_budget = data_function_budget(max_chars_per_func, total_len, notes_per_value as u32 * options.bits_per_note as u32);
+    let tempo_budget = data_function_budget(max_chars_per_func, total_len, TEMPO_BITS);
+    let bend_budget = data_function_budget(max_chars_per_func, total_len, PITCH_BEND_BITS);
+    let functions_count = |changes: &[(u32, u32)], budget| split_changes(changes, max_events_per_func, budget).len();
+    let tempo_cost = if fixed_tempo_bpm.is_some() { 0 } else { functions_count(tempo_changes, tempo_budget) };
+    let base_cost = 5 + tempo_cost + emit_beat_marker as usize;
+    let limit = max_blocks_per_building.unwrap_or(usize::MAX);
+    let notes_per_value = notes_per_value as usize;
+
+    // Parts of groups (group index, channels) and block count for every building.
+    let mut building_parts: Vec<(Vec<(usize, std::ops::Range<usize>)>, usize)> = Vec::new();
+    let mut parts: Vec<(usize, std::ops::Range<usize>)> = Vec::new();
+    let mut cost = base_cost;
+
+    for (g, group) in groups.iter().enumerate() {
+        // OR gate of named group and pitch blocks of bent group, repeated in every building with the group.
+        let bend_cost = if group.pitch_bends.is_empty() { 0 } else { 2 + functions_count(&group.pitch_bends, bend_budget) };
+        let group_cost = group.name.is_some() as usize + bend_cost;
+
+        for (c, changes) in group.note_changes.iter().enumerate() {
+            let tone_gens = group.pitches.len().saturating_sub(c * notes_per_value).min(notes_per_value);
+            let channel_cost = 2 + tone_gens + functions_count(changes, note_budget);
+            let continues_group = parts.last().is_some_and(|p| p.0 == g);
+            let extra_cost = if continues_group { 0 } else { group_cost };
+
+            if !parts.is_empty() && cost + channel_cost + extra_cost > limit {
+                building_parts.push((std::mem::take(&mut parts), cost));
+                cost = base_cost;
+            }
+
+            match parts.last_mut() {
+                Some((last_g, range)) if *last_g == g => range.end = c + 1,
+                _ => {
+                    parts.push((g, c..c + 1));
+                    cost += group_cost;
+                }
+            }
+            cost += channel_cost;
+        }
+    }
+    building_parts.push((parts, cost));
+
+    building_parts
+}
+
+/// Maximal length of generated block names in characters.
+pub const MAX_BLOCK_NAME_LEN: usize = 32;
+
+/// Makes block name from text of MIDI meta event: control characters are dropped,
+/// whitespace is trimmed and the result is truncated to [`MAX_BLOCK_NAME_LEN`] characters.
+fn block_name(text: &[u8]) -> String {
+    String::from_utf8_lossy(text)
+    .chars()
+    .filter(|c| !c.is_control())
+    .collect::<String>()
+    .trim()
+    .chars()
+    .take(MAX_BLOCK_NAME_LEN)
+    .collect()
+}
+
+/// Returns name of the track from its first `TrackName` and `InstrumentName` meta events,
+/// as `track (instrument)` when both are present.
+fn track_name(track: &[TrackEvent]) -> Option<String> {
+    let find = |f: fn(&MetaMessage) -> Option<String>| track.iter().find_map(|e| match &e.kind {
+        TrackEventKind::Meta(meta) => f(meta).filter(|n| !n.is_empty()),
+        _ => None
+    });
+    let name = find(|m| match m { MetaMessage::TrackName(n) => Some(block_name(n)), _ => None });
+    let instrument = find(|m| match m { MetaMessage::InstrumentName(n) => Some(block_name(n)), _ => None });
+
+    let name = match (name, instrument) {
+        (Some(n), Some(i)) if n != i => format!("{} ({})", n, i),
+        (Some(n), _) | (None, Some(n)) => n,
+        (None, None) => return None
+    };
+
+    Some(name.chars().take(MAX_BLOCK_NAME_LEN).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(delta: u32, channel: u8, key: u8, vel: u8) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Midi { channel: channel.into(), message: MidiMessage::NoteOn { key: key.into(), vel: vel.into() } }
+        }
+    }
+
+    fn note_off(delta: u32, channel: u8, key: u8) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Midi { channel: channel.into(), message: MidiMessage::NoteOff { key: key.into(), vel: 0.into() } }
+        }
+    }
+
+    fn controller(delta: u32, channel: u8, controller: u8, value: u8) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Midi { channel: channel.into(), message: MidiMessage::Controller { controller: controller.into(), value: value.into() } }
+        }
+    }
+
+    fn tempo(delta: u32, tempo: u32) -> TrackEvent<'static> {
+        TrackEvent { delta: delta.into(), kind: TrackEventKind::Meta(MetaMessage::Tempo(tempo.into())) }
+    }
+
+    /// Pitch bend by `bend` of full range (-1 to 1).
+    fn pitch_bend(delta: u32, channel: u8, bend: f32) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Midi { channel: channel.into(), message: MidiMessage::PitchBend { bend: midly::PitchBend::from_f32(bend) } }
+        }
+    }
+
+    /// MIDI of format 1 with 96 ticks per quarter note.
+    fn smf(tracks: Vec<Vec<TrackEvent<'static>>>) -> Smf<'static> {
+        Smf { header: Header::new(Format::Parallel, Timing::Metrical(96.into())), tracks }
+    }
+
+    #[test]
+    fn midi_with_only_out_of_range_notes_has_no_playable_notes() {
+        let smf = smf(vec![vec![
+            note_on(0, 0, 20, 100),
+            note_off(10, 0, 20),
+            note_on(0, 0, 120, 100),
+            note_off(10, 0, 120)
+        ]]);
+
+        let result = encode_notes(&smf, &PlayerOptions::default());
+        assert!(matches!(result, Err(Error::NoPlayableNotes { min_pitch: 27, max_pitch: 111 })), "{:?}", result);
+        assert!(matches!(generate_music_player(smf, &PlayerOptions::default()), Err(Error::NoPlayableNotes { .. })));
+    }
+
+    #[test]
+    fn note_on_with_velocity_0_is_note_off() {
+        let smf = smf(vec![vec![note_on(0, 0, 60, 100), note_on(10, 0, 60, 0)]]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default()).unwrap();
+        assert_eq!(encoded.note_changes, vec![vec![(0, 1), (10, 0)]]);
+    }
+
+    #[test]
+    fn note_with_velocity_1_is_played_by_default() {
+        let smf = smf(vec![vec![note_on(0, 0, 60, 1), note_off(10, 0, 60)]]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default()).unwrap();
+        assert_eq!(encoded.note_changes, vec![vec![(0, 1), (10, 0)]]);
+    }
+
+    #[test]
+    fn note_with_min_velocity_is_played_and_quieter_is_ignored() {
+        let smf = smf(vec![vec![
+            note_on(0, 0, 60, 39),
+            note_on(0, 0, 62, 40),
+            note_off(10, 0, 60),
+            note_off(0, 0, 62),
+            // Quiet NoteOn over a sounding note is ignored together with its NoteOff.
+            note_on(0, 0, 64, 100),
+            note_on(5, 0, 64, 39),
+            note_off(5, 0, 64),
+            note_off(10, 0, 64)
+        ]]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default().min_velocity(40)).unwrap();
+        assert_eq!(encoded.pitches, vec![62, 64]);
+        assert_eq!(encoded.note_changes, vec![vec![(0, 1), (10, 2), (30, 0)]]);
+    }
+
+    #[test]
+    fn quiet_note_doesnt_swallow_note_off_of_other_channel() {
+        let smf = smf(vec![vec![
+            note_on(0, 1, 60, 10),
+            note_on(0, 0, 60, 100),
+            note_off(100, 0, 60),
+            note_off(100, 1, 60)
+        ]]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default().min_velocity(50)).unwrap();
+        assert_eq!(encoded.note_changes, vec![vec![(0, 1), (100, 0)]]);
+    }
+
+    #[test]
+    fn sustain_pedal_holds_chord_until_released() {
+        let smf = smf(vec![vec![
+            controller(0, 0, SUSTAIN_CONTROLLER, 127),
+            note_on(0, 0, 60, 100),
+            note_on(0, 0, 64, 100),
+            note_on(0, 0, 67, 100),
+            note_on(0, 1, 72, 100),
+            note_off(10, 0, 60),
+            note_off(0, 0, 64),
+            note_off(0, 0, 67),
+            // Other channel is not held.
+            note_off(0, 1, 72),
+            controller(40, 0, SUSTAIN_CONTROLLER, 0)
+        ]]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default()).unwrap();
+        assert_eq!(encoded.pitches, vec![60, 64, 67, 72]);
+        assert_eq!(encoded.note_changes, vec![vec![(0, 0b1111), (10, 0b0111), (50, 0)]]);
+    }
+
+    #[test]
+    fn note_struck_again_under_sustain_pedal_ends() {
+        // Both strikes are released while the pedal is down, so the note ends with the pedal.
+        let held = smf(vec![vec![
+            controller(0, 0, SUSTAIN_CONTROLLER, 127),
+            note_on(0, 0, 60, 100),
+            note_off(10, 0, 60),
+            note_on(10, 0, 60, 100),
+            note_off(10, 0, 60),
+            controller(20, 0, SUSTAIN_CONTROLLER, 0)
+        ]]);
+        let encoded = encode_notes(&held, &PlayerOptions::default()).unwrap();
+        assert_eq!(encoded.note_changes, vec![vec![(0, 1), (50, 0)]]);
+
+        // Second strike is released after the pedal, so the note ends with its NoteOff.
+        let released = smf(vec![vec![
+            controller(0, 0, SUSTAIN_CONTROLLER, 127),
+            note_on(0, 0, 60, 100),
+            note_off(10, 0, 60),
+            note_on(10, 0, 60, 100),
+            controller(10, 0, SUSTAIN_CONTROLLER, 0),
+            note_off(10, 0, 60)
+        ]]);
+        let encoded = encode_notes(&released, &PlayerOptions::default()).unwrap();
+        assert_eq!(encoded.note_changes, vec![vec![(0, 1), (40, 0)]]);
+    }
+
+    #[test]
+    fn data_changes_round_trip_through_midi() {
+        let source = smf(vec![
+            vec![
+                tempo(0, 400_000),
+                note_on(0, 0, 60, 100),
+                note_on(0, 0, 64, 100),
+                note_off(48, 0, 60),
+                tempo(0, 300_000),
+                note_on(0, 0, 60, 90),
+                note_off(48, 0, 64),
+                note_off(0, 0, 60)
+            ],
+            vec![
+                note_on(24, 1, 67, 80),
+                note_off(96, 1, 67),
+                note_on(0, 1, 72, 100),
+                note_off(10, 1, 72)
+            ]
+        ]);
+        let options = PlayerOptions::default().notes_per_value(2);
+        let encoded = encode_notes(&source, &options).unwrap();
+
+        let rebuilt = data_changes_to_midi(encoded.ppq, &encoded.note_changes, &encoded.pitches, &encoded.tempo_changes, 2, 1).unwrap();
+        let mut bytes = Vec::new();
+        rebuilt.write_std(&mut bytes).unwrap();
+        let reencoded = encode_notes(&Smf::parse(&bytes).unwrap(), &options).unwrap();
+
+        assert_eq!(reencoded.note_changes, encoded.note_changes);
+        assert_eq!(reencoded.pitches, encoded.pitches);
+        assert_eq!(reencoded.tempo_changes, encoded.tempo_changes);
+        assert_eq!(reencoded.total_len, encoded.total_len);
+    }
+
+    #[test]
+    fn data_changes_with_gap_over_delta_time_are_rejected() {
+        let gap = u28::max_value().as_int() + 1;
+        let result = data_changes_to_midi(96, &[vec![(0, 1), (gap, 0)]], &[60], &[(0, DEFAULT_TEMPO)], 24, 1);
+        assert!(matches!(result, Err(Error::GapTooLong { ticks }) if ticks == gap), "{:?}", result.map(|_| ()));
+        assert!(data_changes_to_midi(96, &[vec![(0, 1), (gap - 1, 0)]], &[60], &[(0, DEFAULT_TEMPO)], 24, 1).is_ok());
+    }
+
+    #[test]
+    fn song_longer_than_u32_ticks_is_rejected() {
+        let max_delta = u28::max_value().as_int();
+        let mut track = vec![note_on(0, 0, 60, 100), note_off(10, 0, 60)];
+        track.extend((0..16).map(|_| tempo(max_delta, DEFAULT_TEMPO)));
+        // Delta of the last event that lands exactly at u32::MAX.
+        let last_delta = u32::MAX - 10 - 16 * max_delta;
+
+        let longest = smf(vec![[&track[..], &[tempo(last_delta, DEFAULT_TEMPO)]].concat()]);
+        assert_eq!(encode_notes(&longest, &PlayerOptions::default()).unwrap().total_len, u32::MAX);
+
+        let too_long = smf(vec![[&track[..], &[tempo(last_delta + 1, DEFAULT_TEMPO)]].concat()]);
+        let result = encode_notes(&too_long, &PlayerOptions::default());
+        assert!(matches!(result, Err(Error::SongTooLong { ticks }) if ticks == u32::MAX as u64 + 1), "{:?}", result);
+    }
+
+    /// Song of 400 ticks at 500000 us per quarter with `loopStart` at 100 and `loopEnd` at 200.
+    fn song_with_loop_markers() -> Smf<'static> {
+        let marker = |delta: u32, text: &'static str| TrackEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Meta(MetaMessage::Marker(text.as_bytes()))
+        };
+        smf(vec![
+            vec![tempo(0, 500_000), marker(100, LOOP_START_MARKER), marker(100, LOOP_END_MARKER)],
+            vec![note_on(0, 0, 60, 100), note_off(400, 0, 60)]
+        ])
+    }
+
+    /// Song positions in ticks of main block of `building` over `steps` updates, at tempo
+    /// 500000 us per quarter.
+    fn main_block_positions(building: &Building, steps: usize) -> Vec<f32> {
+        let Some(Metadata { type_settings: TypeSettings::MathBlock { function, .. }, .. }) = &building.blocks[0].metadata else {
+            panic!("main block is not a math block");
+        };
+        let tempo = 500_000.0 / 2f32.powi(TEMPO_BITS as i32);
+        let mut value = 0.0;
+        (0..steps).map(|_| {
+            value = codegen::tests::eval(function, &[("A", 1.0), ("B", 0.0), ("C", tempo), ("Lval", value)]).0;
+            value * 400.0
+        }).collect()
+    }
+
+    #[test]
+    fn repeated_song_loops_between_markers() {
+        let building = generate_music_player(song_with_loop_markers(), &PlayerOptions::default().repeat(true)).unwrap();
+
+        // 3.84 ticks per update: the song plays from 0, then wraps from loopEnd back to loopStart.
+        let positions = main_block_positions(&building, 200);
+        let wraps: Vec<(f32, f32)> = positions.windows(2).filter(|w| w[1] < w[0]).map(|w| (w[0], w[1])).collect();
+        assert!(wraps.len() >= 2, "{:?}", wraps);
+        for (before, after) in wraps {
+            assert!((196.0..200.0).contains(&before), "{} -> {}", before, after);
+            assert!((100.0..104.0).contains(&after), "{} -> {}", before, after);
+        }
+        assert!(positions.iter().all(|&p| p < 200.0));
+        assert!(positions[..20].iter().any(|&p| p < 100.0));
+    }
+
+    #[test]
+    fn loop_markers_are_ignored_without_repeat() {
+        let building = generate_music_player(song_with_loop_markers(), &PlayerOptions::default()).unwrap();
+
+        let positions = main_block_positions(&building, 200);
+        assert!(positions.windows(2).all(|w| w[1] > w[0]));
+        assert!(positions[199] > 400.0);
+    }
+
+    #[test]
+    fn measure_marker_pulses_on_first_beat_of_every_bar() {
+        // Two bars of 4/4, then three bars of 3/4, 96 ticks per beat. Without time
+        // signature at 0 the song starts in 4/4.
+        let bar_starts = [0, 384, 768, 1056, 1344];
+        let total_len = 2 * 384 + 3 * 288;
+        for time_signatures in [&[(0, 4, 2), (768, 3, 2)][..], &[(768, 3, 2)]] {
+            let function = measure_marker_function(time_signatures, 96, total_len).unwrap();
+            for tick in 0..total_len {
+                let x = tick as f32 + 0.5;
+                let (pulse, _) = codegen::tests::eval(&function, &[("A", x / total_len as f32)]);
+                let expected = bar_starts.iter().any(|&start| (start..start + 96).contains(&tick));
+                assert_eq!(pulse, expected as u8 as f32, "tick {} of {:?}", tick, time_signatures);
+            }
+        }
+    }
+
+    #[test]
+    fn pan_near_center_is_centered() {
+        assert_eq!([0, 47, 48, 63, 64, 80, 81, 127].map(Pan::from_midi), [
+            Pan::Left, Pan::Left, Pan::Center, Pan::Center, Pan::Center, Pan::Center, Pan::Right, Pan::Right
+        ]);
+    }
+
+    #[test]
+    fn stereo_splits_panned_channels_into_left_and_right_groups() {
+        let smf = smf(vec![
+            vec![
+                controller(0, 0, PAN_CONTROLLER, 10),
+                controller(0, 1, PAN_CONTROLLER, 63),
+                controller(0, 2, PAN_CONTROLLER, 120),
+                // Only the first pan of a channel counts.
+                controller(10, 0, PAN_CONTROLLER, 120)
+            ],
+            vec![
+                note_on(0, 0, 60, 100),
+                note_on(0, 1, 62, 100),
+                note_on(0, 2, 64, 100),
+                note_on(0, 3, 65, 100),
+                note_off(20, 0, 60),
+                note_off(0, 1, 62),
+                note_off(0, 2, 64),
+                note_off(0, 3, 65)
+            ]
+        ]);
+        let options = PlayerOptions::default().stereo(true);
+
+        let (_, groups) = encode_groups(&smf, &options).unwrap();
+        let sides: Vec<_> = groups.iter().map(|g| (g.name.as_deref(), g.pan, &g.pitches[..])).collect();
+        assert_eq!(sides, vec![
+            (None, Pan::Center, &[62, 65][..]),
+            (Some("left"), Pan::Left, &[60][..]),
+            (Some("right"), Pan::Right, &[64][..])
+        ]);
+
+        // Tone generators of every side are moved to it.
+        let building = generate_music_player(smf, &options).unwrap();
+        let mut tone_gens: Vec<(u32, f32)> = building.blocks.iter()
+        .filter(|b| b.id == options.palette.tone_generator)
+        .map(|b| (b.metadata.as_ref().unwrap().values[0].round() as u32, b.position[0]))
+        .collect();
+        tone_gens.sort_by_key(|t| t.0);
+        let freq = |pitch| pitch_to_freq(pitch, 440.0).round() as u32;
+        assert_eq!(tone_gens, vec![(freq(60), -STEREO_SPREAD), (freq(62), 0.0), (freq(64), STEREO_SPREAD), (freq(65), 0.0)]);
+    }
+
+    #[test]
+    fn pitch_bend_is_ignored_with_range_0() {
+        let smf = smf(vec![vec![note_on(0, 0, 60, 100), pitch_bend(50, 0, 1.0), pitch_bend(50, 0, 0.0), note_off(50, 0, 60)]]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default().pitch_bend_range(0)).unwrap();
+        assert_eq!(encoded.pitches, vec![60]);
+        assert_eq!(encoded.note_changes, vec![vec![(0, 1), (150, 0)]]);
+        assert!(encoded.pitch_bends.iter().all(|b| b.is_empty()));
+    }
+
+    #[test]
+    fn pitch_bends_are_collected_per_channel_without_moving_notes() {
+        let smf = smf(vec![vec![
+            note_on(0, 0, 60, 100),
+            note_on(0, 1, 64, 100),
+            note_on(0, DRUM_CHANNEL, 40, 100),
+            pitch_bend(50, 0, 1.0),
+            // Vibrato-sized bend is kept.
+            pitch_bend(50, 0, -0.05),
+            pitch_bend(0, DRUM_CHANNEL, 1.0),
+            note_off(50, 0, 60),
+            note_off(0, 1, 64),
+            note_off(0, DRUM_CHANNEL, 40)
+        ]]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default().drums(DrumMode::Keep)).unwrap();
+        assert_eq!(encoded.pitches, vec![40, 60, 64]);
+        assert_eq!(encoded.note_changes, vec![vec![(0, 0b111), (150, 0)]]);
+        assert_eq!(encoded.pitch_bends[0], vec![(0, PITCH_BEND_CENTER), (50, 16383), (100, 7783)]);
+        assert!(encoded.pitch_bends[1..].iter().all(|b| b.is_empty()));
+    }
+
+    #[test]
+    fn bent_channel_gets_pitch_block_driving_its_tone_generators() {
+        let smf = smf(vec![vec![
+            note_on(0, 0, 60, 100),
+            note_on(0, 1, 60, 100),
+            pitch_bend(50, 0, 0.5),
+            note_off(50, 0, 60),
+            note_off(0, 1, 60)
+        ]]);
+        let options = PlayerOptions::default();
+
+        let (_, groups) = encode_groups(&smf, &options).unwrap();
+        let parts: Vec<_> = groups.iter().map(|g| (g.name.as_deref(), &g.pitches[..], &g.pitch_bends[..])).collect();
+        assert_eq!(parts, vec![(None, &[60][..], &[][..]), (Some("channel 0"), &[60][..], &[(0, PITCH_BEND_CENTER), (50, 12288)][..])]);
+
+        let building = generate_music_player(smf.clone(), &options).unwrap();
+        let blocks = &building.blocks;
+        assert_eq!(estimate_block_count(&smf, &options).unwrap(), blocks.len());
+        // Split buildings repeat the pitch block with its group.
+        let split = options.clone().max_blocks_per_building(Some(1));
+        let buildings = generate_music_players(smf.clone(), &split).unwrap();
+        assert_eq!(buildings.len(), 2);
+        assert_eq!(estimate_block_count(&smf, &split).unwrap(), buildings.iter().map(|b| b.blocks.len()).sum::<usize>());
+        let function = |i: usize| match &blocks[i].metadata {
+            Some(Metadata { type_settings: TypeSettings::MathBlock { function, .. }, .. }) => function.as_str(),
+            _ => ""
+        };
+        let pitch_block = (0..blocks.len()).find(|&i| function(i).starts_with("b=")).unwrap();
+        let tone_gens: Vec<usize> = (0..blocks.len()).filter(|&i| blocks[i].id == options.palette.tone_generator).collect();
+        assert_eq!(tone_gens.len(), 2);
+        // Only the tone generator of the bent channel is driven by the pitch block, besides its decoder.
+        let bent_tone_gen = blocks[pitch_block].connections[0] as usize;
+        assert_eq!(blocks[pitch_block].connections.len(), 1);
+        let drivers: Vec<usize> = (0..blocks.len()).filter(|&i| blocks[i].connections.contains(&(bent_tone_gen as u16))).collect();
+        assert_eq!(drivers.len(), 2);
+        assert_eq!(drivers[0], pitch_block);
+        assert_eq!(function(drivers[1]), build_decoder_function(options.notes_per_value as usize));
+
+        // Bend data blocks feed the pitch block through its input, frequency follows the bend.
+        let pitch_input = (0..blocks.len()).find(|&i| blocks[i].connections == [pitch_block as u16]).unwrap();
+        let data_blocks: Vec<usize> = (0..blocks.len()).filter(|&i| blocks[i].connections == [pitch_input as u16]).collect();
+        let frequency_at = |time: f32| {
+            let bend: f32 = data_blocks.iter().map(|&i| codegen::tests::eval(function(i), &[("A", time / 100.0)]).0).sum();
+            codegen::tests::eval(function(pitch_block), &[("A", bend)]).1[&1]
+        };
+        let freq = pitch_to_freq(60, 440.0);
+        assert!((frequency_at(20.0) - freq).abs() < 0.01, "{}", frequency_at(20.0));
+        assert!((frequency_at(70.0) - freq * 2f32.powf(1.0 / 12.0)).abs() < 0.01, "{}", frequency_at(70.0));
+    }
+
+    #[test]
+    fn retriggered_note_keeps_sounding_and_zero_length_note_is_silent() {
+        let smf = smf(vec![
+            vec![note_on(0, 0, 60, 100), note_off(10, 0, 60), note_on(20, 0, 62, 100), note_off(0, 0, 62)],
+            // Retrigger from another track: its NoteOn comes before NoteOff of the first track.
+            vec![note_on(10, 0, 60, 100), note_off(10, 0, 60)]
+        ]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default()).unwrap();
+        assert_eq!(encoded.pitches, vec![60, 62]);
+        assert_eq!(encoded.note_changes, vec![vec![(0, 0b01), (20, 0)]]);
+    }
+
+    #[test]
+    fn lead_in_delays_first_notes_at_song_start_tempo() {
+        let smf = smf(vec![
+            vec![tempo(0, 400_000), tempo(200, 300_000)],
+            vec![note_on(100, 0, 60, 100), note_on(20, 0, 62, 100), note_off(20, 0, 60), note_off(0, 0, 62)]
+        ]);
+        let options = PlayerOptions::default().notes_per_value(1);
+
+        for lead_in in [0, 50] {
+            let encoded = encode_notes(&smf, &options.clone().lead_in_ticks(lead_in)).unwrap();
+            // No tone generator is keyed on before the first note.
+            assert_eq!(encoded.note_changes, vec![
+                vec![(100 + lead_in, 1), (140 + lead_in, 0)],
+                vec![(120 + lead_in, 1), (140 + lead_in, 0)]
+            ]);
+            let mut tempo_changes = vec![(lead_in, 400_000), (200 + lead_in, 300_000)];
+            if lead_in > 0 {
+                tempo_changes.insert(0, (0, 400_000));
+            }
+            assert_eq!(encoded.tempo_changes, tempo_changes);
+            assert_eq!(encoded.total_len, 200 + lead_in);
+        }
+    }
+
+    #[test]
+    fn tracks_of_format_2_play_one_after_another() {
+        let end_of_track = |delta: u32| TrackEvent { delta: delta.into(), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) };
+        let mut smf = smf(vec![
+            vec![note_on(0, 0, 60, 100), note_off(10, 0, 60), end_of_track(10)],
+            vec![note_on(0, 0, 62, 100), note_off(10, 0, 62), end_of_track(0)]
+        ]);
+
+        let parallel = encode_notes(&smf, &PlayerOptions::default()).unwrap();
+        assert_eq!(parallel.note_changes, vec![vec![(0, 0b11), (10, 0)]]);
+
+        smf.header.format = Format::Sequential;
+        let sequential = encode_notes(&smf, &PlayerOptions::default()).unwrap();
+        assert_eq!(sequential.pitches, vec![60, 62]);
+        assert_eq!(sequential.note_changes, vec![vec![(0, 0b01), (10, 0), (20, 0b10), (30, 0)]]);
+        assert_eq!(sequential.total_len, 30);
+    }
+
+    #[test]
+    fn song_with_first_tempo_mid_song_starts_at_default_tempo() {
+        let smf = smf(vec![
+            vec![tempo(192, 300_000)],
+            vec![note_on(0, 0, 60, 100), note_off(384, 0, 60)]
+        ]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default()).unwrap();
+        assert_eq!(encoded.tempo_changes, vec![(0, DEFAULT_TEMPO), (192, 300_000)]);
+    }
+
+    #[test]
+    fn zero_tempo_is_clamped_to_min_tempo() {
+        let smf = smf(vec![vec![
+            tempo(0, 0),
+            note_on(0, 0, 60, 100),
+            tempo(10, 1),
+            tempo(10, 600_000),
+            note_off(10, 0, 60)
+        ]]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default()).unwrap();
+        assert_eq!(encoded.tempo_changes, vec![(0, MIN_TEMPO), (10, MIN_TEMPO), (20, 600_000)]);
+
+        let building = generate_music_player(smf, &PlayerOptions::default()).unwrap();
+        for block in &building.blocks {
+            if let Some(Metadata { type_settings: TypeSettings::MathBlock { function, .. }, .. }) = &block.metadata {
+                assert!(!function.contains("inf") && !function.contains("NaN"), "{}", function);
+            }
+        }
+    }
+
+    /// Chord of keys 60-69 at time 0 released at 100, with velocities in order of `velocities`.
+    fn ten_note_chord(velocities: [u8; 10]) -> Smf<'static> {
+        let ons = (0..10).map(|i| note_on(0, 0, 60 + i, velocities[i as usize]));
+        let offs = (0..10).map(|i| note_off(if i == 0 { 100 } else { 0 }, 0, 60 + i));
+        smf(vec![ons.chain(offs).collect()])
+    }
+
+    #[test]
+    fn ten_note_cluster_limited_to_4_keeps_newest_notes() {
+        let options = PlayerOptions::default().max_polyphony(Some(4));
+
+        let chord = encode_notes(&ten_note_chord([100; 10]), &options).unwrap();
+        assert_eq!(chord.note_changes, vec![vec![(0, 0b1111000000), (100, 0)]]);
+
+        // Notes starting one after another, every new one cuts the oldest.
+        let track = (0..10).map(|i| note_on(if i == 0 { 0 } else { 10 }, 0, 60 + i, 100))
+        .chain((0..10).map(|i| note_off(if i == 0 { 100 } else { 0 }, 0, 60 + i)))
+        .collect();
+        let arpeggio = encode_notes(&smf(vec![track]), &options).unwrap();
+        assert_eq!(arpeggio.note_changes, vec![vec![
+            (0, 0b0000000001),
+            (10, 0b0000000011),
+            (20, 0b0000000111),
+            (30, 0b0000001111),
+            (40, 0b0000011110),
+            (50, 0b0000111100),
+            (60, 0b0001111000),
+            (70, 0b0011110000),
+            (80, 0b0111100000),
+            (90, 0b1111000000),
+            (190, 0)
+        ]]);
+    }
+
+    #[test]
+    fn ten_note_cluster_limited_to_4_keeps_loudest_notes() {
+        let smf = ten_note_chord([100, 20, 90, 30, 80, 10, 70, 40, 60, 50]);
+        let options = PlayerOptions::default().max_polyphony(Some(4)).voice_stealing(VoiceStealing::LowestVelocity);
+
+        // Notes quieter than all sounding ones are never played, so they have no pitch index.
+        let encoded = encode_notes(&smf, &options).unwrap();
+        assert_eq!(encoded.pitches, vec![60, 61, 62, 63, 64, 66]);
+        assert_eq!(encoded.note_changes, vec![vec![(0, 0b110101), (100, 0)]]);
+    }
+
+    #[test]
+    fn zero_length_note_doesnt_take_voice() {
+        let smf = smf(vec![vec![
+            note_on(0, 0, 62, 100),
+            note_on(10, 0, 60, 100),
+            note_off(0, 0, 60),
+            note_on(90, 0, 60, 100),
+            note_on(20, 0, 64, 100),
+            note_off(30, 0, 60),
+            note_off(50, 0, 62),
+            note_off(0, 0, 64)
+        ]]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default().max_polyphony(Some(2))).unwrap();
+        assert_eq!(encoded.pitches, vec![60, 62, 64]);
+        assert_eq!(encoded.note_changes, vec![vec![(0, 0b010), (100, 0b011), (120, 0b101), (150, 0b100), (200, 0)]]);
+    }
+
+    #[test]
+    fn clipped_song_starts_with_notes_sounding_at_start() {
+        let smf = smf(vec![vec![
+            note_on(0, 0, 62, 100),
+            // Zero-length note before the start isn't carried into it.
+            note_on(10, 0, 60, 100),
+            note_off(0, 0, 60),
+            note_on(20, 0, 60, 100),
+            note_off(20, 0, 60),
+            note_off(0, 0, 62),
+            note_on(10, 0, 64, 100),
+            note_off(10, 0, 64)
+        ]]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default().start_ticks(20).end_ticks(Some(55))).unwrap();
+        assert_eq!(encoded.pitches, vec![60, 62]);
+        assert_eq!(encoded.note_changes, vec![vec![(0, 0b10), (10, 0b11), (30, 0)]]);
+        assert_eq!(encoded.total_len, 35);
+    }
+
+    #[test]
+    fn clipped_song_starts_with_tempo_and_signature_at_start() {
+        let signature = |delta: u32, numerator: u8| TrackEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, 2, 24, 8))
+        };
+        let smf = smf(vec![
+            vec![tempo(0, 400_000), signature(0, 4), tempo(15, 300_000), signature(0, 3), tempo(15, 250_000), tempo(20, 200_000)],
+            vec![note_on(0, 0, 60, 100), note_off(60, 0, 60)]
+        ]);
+
+        let encoded = encode_notes(&smf, &PlayerOptions::default().start_ticks(20).end_ticks(Some(40))).unwrap();
+        assert_eq!(encoded.tempo_changes, vec![(0, 300_000), (10, 250_000)]);
+        assert_eq!(encoded.time_signatures, vec![(0, 3, 2)]);
+        assert_eq!(encoded.note_changes, vec![vec![(0, 1)]]);
+        assert_eq!(encoded.total_len, 20);
+    }
+
+    #[test]
+    fn tracks_are_clipped_as_whole_song_when_grouped() {
+        // Conductor track ends long before the time range starts.
+        let smf = smf(vec![
+            vec![tempo(0, 400_000), tempo(10, 300_000)],
+            vec![note_on(150, 0, 60, 100), note_off(150, 0, 60)]
+        ]);
+
+        let (encoded, groups) = encode_groups(&smf, &PlayerOptions::default().group_by_track(true).start_ticks(200)).unwrap();
+        assert_eq!(encoded.total_len, 100);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name.as_deref(), Some("Track 1"));
+        assert_eq!(groups[0].note_changes, vec![vec![(0, 1)]]);
+    }
+
+    #[test]
+    fn velocity_levels_are_encoded_and_decoded_with_2_bits_per_note() {
+        let smf = smf(vec![vec![
+            note_on(0, 0, 60, 127),
+            note_on(0, 0, 62, 40),
+            note_on(0, 0, 64, 1),
+            note_on(0, 0, 65, 80),
+            note_off(10, 0, 60),
+            note_off(0, 0, 62),
+            note_off(0, 0, 64),
+            note_off(0, 0, 65)
+        ]]);
+        let options = PlayerOptions::default().notes_per_value(12).bits_per_note(2);
+
+        // Levels 3, 1, 1 and 2 in bits 0-1, 2-3, 4-5 and 6-7.
+        let encoded = encode_notes(&smf, &options).unwrap();
+        assert_eq!(encoded.note_changes, vec![vec![(0, 0b10_01_01_11), (10, 0)]]);
+
+        let decoder = build_layered_decoder_function(12, 2);
+        let (_, outputs) = crate::codegen::tests::eval(&decoder, &[("A", 0b10_01_01_11 as f32 / 2f32.powi(24))]);
+        let levels: Vec<f32> = (1..=12).map(|k| outputs[&k] * 3.0).collect();
+        assert_eq!(levels, [3.0, 1.0, 1.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn velocity_levels_round_trip_through_midi() {
+        let smf = smf(vec![vec![
+            note_on(0, 0, 60, 40),
+            note_on(0, 0, 62, 80),
+            // Louder retrigger changes the level of a sounding note.
+            note_on(10, 0, 60, 120),
+            note_off(10, 0, 60),
+            note_off(0, 0, 60),
+            note_off(0, 0, 62)
+        ]]);
+        let options = PlayerOptions::default().notes_per_value(12).bits_per_note(2);
+        let encoded = encode_notes(&smf, &options).unwrap();
+        assert_eq!(encoded.note_changes, vec![vec![(0, 0b10_01), (10, 0b10_11), (20, 0)]]);
+
+        let rebuilt = data_changes_to_midi(encoded.ppq, &encoded.note_changes, &encoded.pitches, &encoded.tempo_changes, 12, 2).unwrap();
+        let reencoded = encode_notes(&rebuilt, &options).unwrap();
+        assert_eq!(reencoded.pitches, vec![60, 62]);
+        assert_eq!(reencoded.note_changes, encoded.note_changes);
+    }
+
+    #[test]
+    fn huge_deltas_are_too_long_instead_of_wrapping() {
+        let max_delta = u28::max_value().as_int();
+        // Notes after the overflow would wrap before the first ones.
+        let track: Vec<_> = (0..17).map(|i| note_on(max_delta, 0, 60 + i, 100)).collect();
+        let result = encode_notes(&smf(vec![track]), &PlayerOptions::default());
+        assert!(matches!(result, Err(Error::SongTooLong { ticks: 4_563_402_735 })), "{:?}", result);
+
+        // Lead-in pushes a song that fits on its own over the limit.
+        let track: Vec<_> = (0..16).map(|i| note_on(max_delta, 0, 60 + i, 100)).collect();
+        let smf = smf(vec![track]);
+        assert!(encode_notes(&smf, &PlayerOptions::default()).is_ok());
+        let result = encode_notes(&smf, &PlayerOptions::default().lead_in_ticks(16));
+        assert!(matches!(result, Err(Error::SongTooLong { ticks }) if ticks == u32::MAX as u64 + 1), "{:?}", result);
+    }
 }
@@ -1,12 +1,33 @@
 use sw_structure_io::io::WriteBuilding;
-use clap::Parser;
+use sw_structure_io::structs::Building;
+use clap::{Parser, ValueEnum};
 use std::fs::File;
-use std::io::Read;
-use midly::Smf;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use midly::{Smf, TrackEventKind, MidiMessage, MetaMessage};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
 use anyhow::{Result, Context};
 
-use midi2swstruct::generate_music_player;
+use midi2swstruct::{generate_music_players_with_stats, DrumMode, PlayerOptions, VoiceStealing, MAX_NOTES_PER_VALUE, MIN_TEMPO};
+use midi2swstruct::json::building_to_json;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Sandbox World structure file.
+    #[value(alias = "swstruct")]
+    Structure,
+    /// Human-readable JSON, independent of structure version.
+    Json
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Structure => "structure",
+            OutputFormat::Json      => "json"
+        }
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(name = "midi2swstruct")]
@@ -16,36 +37,49 @@ use midi2swstruct::generate_music_player;
     long_about  = "Converts MIDI-file to Sandbox World structure file with music player, that contains data from MIDI-file."
 )]
 struct Args {
-    /// Input MIDI-file.
-    #[arg(value_name = "INPUT", required = true)]
-    input: PathBuf,
+    /// Input MIDI-files or glob patterns (`*` and `?` in file names), `-` to read from stdin.
+    #[arg(value_name = "INPUT", required = true, num_args = 1..)]
+    inputs: Vec<String>,
 
-    /// Optional output path.
+    /// Optional output path. Only for a single input.
     #[arg(short, long)]
     output: Option<PathBuf>,
 
+    /// Directory for output files, named after inputs. Current directory by default.
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+
     /// Write output to stdout instead of file.
     #[arg(long, default_value_t = false)]
     stdout: bool,
 
     /// Minimal note pitch.
-    #[arg(long, default_value = "27", value_parser = clap::value_parser!(u8).range(0..127))]
+    #[arg(long, default_value = "27", value_parser = clap::value_parser!(u8).range(0..=127))]
     min_pitch: u8,
 
     /// Maximal note pitch.
-    #[arg(long, default_value = "111", value_parser = clap::value_parser!(u8).range(0..127))]
+    #[arg(long, default_value = "111", value_parser = clap::value_parser!(u8).range(0..=127))]
     max_pitch: u8,
 
-    /// Structure version.
-    #[arg(short, long, default_value = "0")]
-    structure_version: u8,
+    /// Structure version. Comma-separated list writes one file per version.
+    #[arg(short, long, default_value = "0", value_delimiter = ',')]
+    structure_version: Vec<u8>,
 
     /// Maximal amount of events per function.
     #[arg(long, default_value = "1024")]
     max_events_per_func: usize,
 
-    /// Minimal velocity for note to be flagged as active.
-    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u8).range(0..127))]
+    /// Maximal length of math block function in characters.
+    #[arg(long)]
+    max_chars_per_func: Option<usize>,
+
+    /// Keep playing notes that are still on when the song ends instead of silencing them.
+    /// Has no effect with --repeat.
+    #[arg(long, default_value_t = false)]
+    hold_last: bool,
+
+    /// Minimal velocity for note to be flagged as active. Quieter notes are ignored.
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u8).range(0..=127))]
     min_velocity: u8,
 
     /// If true, music will repeat.
@@ -53,64 +87,540 @@ struct Args {
     repeat: bool,
 
     /// How many note changes can be encoded in one value.
-    #[arg(short, long, default_value = "24", value_parser = clap::value_parser!(u8).range(1..=24))]
-    notes_per_value: u8
+    #[arg(short, long, default_value = "24", value_parser = clap::value_parser!(u8).range(1..=MAX_NOTES_PER_VALUE as i64))]
+    notes_per_value: u8,
+
+    /// Bits per note, more than 1 stores velocity levels (notes per value * bits must be at most 24).
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u8).range(1..=8))]
+    bits_per_note: u8,
+
+    /// Frequency of A4 in Hz (tuning reference).
+    #[arg(long, default_value = "440")]
+    tuning: f32,
+
+    /// Volume of tone generators (0-100).
+    #[arg(long, default_value = "100")]
+    volume: f32,
+
+    /// Pitch bend range in semitones. 0 ignores bends.
+    #[arg(long, default_value = "2")]
+    pitch_bend_range: u8,
+
+    /// Keep notes of every track in separate channels behind a named OR gate.
+    #[arg(long, default_value_t = false)]
+    group_by_track: bool,
+
+    /// Let track groups playing the same pitch share one tone generator.
+    #[arg(long, default_value_t = false)]
+    dedupe_tones: bool,
+
+    /// Split notes into left and right groups by pan (CC10) of their MIDI channel,
+    /// channels panned near the center stay in the center.
+    #[arg(long, default_value_t = false)]
+    stereo: bool,
+
+    /// Add math block named `measure` that outputs 1 during the first beat of every measure.
+    #[arg(long, default_value_t = false)]
+    beat_marker: bool,
+
+    /// Play at constant tempo in BPM, ignoring tempo changes of MIDI.
+    #[arg(long, value_name = "BPM")]
+    fixed_tempo: Option<f32>,
+
+    /// Silence in ticks before the song starts.
+    #[arg(long, value_name = "TICKS", default_value = "0")]
+    lead_in: u32,
+
+    /// Convert only the part of MIDI starting at this tick.
+    #[arg(long, value_name = "TICKS", default_value = "0")]
+    start_ticks: u32,
+
+    /// Convert only the part of MIDI before this tick.
+    #[arg(long, value_name = "TICKS")]
+    end_ticks: Option<u32>,
+
+    /// Comma-separated list of MIDI channels to convert (0-15). All channels if not set.
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u8).range(0..16))]
+    channels: Option<Vec<u8>>,
+
+    /// Drum channel (10) handling: skip, map (to fixed pitches) or keep.
+    #[arg(long, default_value = "skip")]
+    drums: DrumMode,
+
+    /// Maximal amount of notes sounding at once, extra notes cut others.
+    #[arg(long)]
+    max_polyphony: Option<usize>,
+
+    /// Which note is cut over --max-polyphony: oldest or lowest-velocity.
+    #[arg(long, default_value = "oldest")]
+    voice_stealing: VoiceStealing,
+
+    /// Print conversion statistics to stderr.
+    #[arg(long, default_value_t = false)]
+    stats: bool,
+
+    /// Maximal amount of blocks per building. Bigger players are split into several files.
+    #[arg(long)]
+    max_blocks: Option<usize>,
+
+    /// Convert and serialize without writing, print size and block count of output to stderr.
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Print summary of MIDI tracks and exit without generating building.
+    #[arg(long, default_value_t = false)]
+    list_tracks: bool,
+
+    /// Output format.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Structure)]
+    format: OutputFormat
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+/// Writes building in selected format.
+fn write_output(w: &mut impl Write, building: &Building, format: OutputFormat, structure_version: u8) -> Result<()> {
+    match format {
+        OutputFormat::Structure => w.write_building(building, structure_version)
+        .context("Failed to serialize building")?,
+        OutputFormat::Json => w.write_all(building_to_json(building).as_bytes())
+        .context("Failed to write JSON")?
+    }
+    Ok(())
+}
+
+/// Prints track count, per-track event counts, channels, pitch range and tempo changes.
+fn print_track_list(smf: &Smf) {
+    println!("Format: {:?}, timing: {:?}", smf.header.format, smf.header.timing);
+    println!("Tracks: {}", smf.tracks.len());
+
+    for (i, track) in smf.tracks.iter().enumerate() {
+        let mut name = None;
+        let mut channels = [false; 16];
+        let mut pitch_range: Option<(u8, u8)> = None;
+        let mut notes = 0;
+        let mut tempo_changes = Vec::new();
+        let mut abs_time = 0u64;
+
+        for event in track {
+            abs_time += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Midi { channel, message } => {
+                    channels[channel.as_int() as usize] = true;
+                    if let MidiMessage::NoteOn { key, vel } = message && vel > 0 {
+                        let key = key.as_int();
+                        notes += 1;
+                        pitch_range = Some(match pitch_range {
+                            Some((min, max)) => (min.min(key), max.max(key)),
+                            None => (key, key)
+                        });
+                    }
+                },
+                TrackEventKind::Meta(MetaMessage::TrackName(n)) => {
+                    name = Some(String::from_utf8_lossy(n).into_owned());
+                },
+                TrackEventKind::Meta(MetaMessage::Tempo(t)) => {
+                    // Clamped as in conversion, zero tempo would be infinitely fast.
+                    tempo_changes.push((abs_time, 60_000_000.0 / t.as_int().max(MIN_TEMPO) as f64));
+                },
+                _ => {}
+            }
+        }
+
+        let channels: Vec<String> = (0..16).filter(|&c| channels[c]).map(|c| c.to_string()).collect();
+
+        println!();
+        println!("Track {}{}", i, name.map(|n| format!(" ({})", n)).unwrap_or_default());
+        println!("  Events: {}, notes: {}", track.len(), notes);
+        println!("  Channels: {}", if channels.is_empty() { "-".into() } else { channels.join(",") });
+        match pitch_range {
+            Some((min, max)) => println!("  Pitch range: {}-{}", min, max),
+            None => println!("  Pitch range: -")
+        }
+        for (time, bpm) in tempo_changes {
+            println!("  Tempo at tick {}: {:.2} BPM", time, bpm);
+        }
+    }
+}
+
+impl Args {
+    fn player_options(&self) -> PlayerOptions {
+        PlayerOptions::default()
+        .notes_per_value(self.notes_per_value)
+        .min_pitch(self.min_pitch)
+        .max_pitch(self.max_pitch)
+        .min_velocity(self.min_velocity)
+        .repeat(self.repeat)
+        .max_events_per_func(self.max_events_per_func)
+        .max_chars_per_func(self.max_chars_per_func)
+        .a4_hz(self.tuning)
+        .master_volume(self.volume)
+        .channels(self.channels.clone())
+        .drums(self.drums)
+        .pitch_bend_range(self.pitch_bend_range)
+        .group_by_track(self.group_by_track)
+        .emit_beat_marker(self.beat_marker)
+        .fixed_tempo_bpm(self.fixed_tempo)
+        .hold_last(self.hold_last)
+        .dedupe_tones(self.dedupe_tones)
+        .max_blocks_per_building(self.max_blocks)
+        .collect_diagnostics(true)
+        .max_polyphony(self.max_polyphony)
+        .voice_stealing(self.voice_stealing)
+        .lead_in_ticks(self.lead_in)
+        .stereo(self.stereo)
+        .start_ticks(self.start_ticks)
+        .end_ticks(self.end_ticks)
+        .bits_per_note(self.bits_per_note)
+    }
+
+    /// Checks relations between arguments that clap can't check on its own.
+    fn validate(&self) -> Result<()> {
+        if self.min_pitch > self.max_pitch {
+            anyhow::bail!(
+                "--min-pitch ({}) is greater than --max-pitch ({}), no notes would be played",
+                self.min_pitch,
+                self.max_pitch
+            );
+        }
+        if self.inputs.len() > 1 || self.inputs.iter().any(|i| i.contains(['*', '?'])) {
+            if self.output.is_some() {
+                anyhow::bail!("--output can't be used with several inputs, use --out-dir instead");
+            }
+            if self.stdout {
+                anyhow::bail!("--stdout can't be used with several inputs");
+            }
+            if self.inputs.iter().any(|i| i == "-") {
+                anyhow::bail!("stdin (`-`) can't be used with several inputs");
+            }
+        }
+        if self.stdout && self.format == OutputFormat::Structure && self.structure_version.len() > 1 {
+            anyhow::bail!("--stdout can't be used with several structure versions");
+        }
+        if self.output.is_some() && self.out_dir.is_some() {
+            anyhow::bail!("--output and --out-dir can't be used together");
+        }
+        if let Some(bpm) = self.fixed_tempo && !(bpm.is_finite() && bpm > 0.0) {
+            anyhow::bail!("--fixed-tempo must be a positive number of BPM, got {}", bpm);
+        }
+        if !(self.tuning.is_finite() && self.tuning > 0.0) {
+            anyhow::bail!("--tuning must be a positive frequency in Hz, got {}", self.tuning);
+        }
+        if !self.volume.is_finite() {
+            anyhow::bail!("--volume must be a number from 0 to 100, got {}", self.volume);
+        }
+        if let Some(end) = self.end_ticks && end <= self.start_ticks {
+            anyhow::bail!("--end-ticks ({}) must be greater than --start-ticks ({})", end, self.start_ticks);
+        }
+        Ok(())
+    }
+}
+
+/// Inserts suffix (building index or structure version) before extension:
+/// `out.structure` -> `out.0.structure`.
+fn suffixed_path(path: &Path, suffix: impl std::fmt::Display) -> PathBuf {
+    let mut name = path
+    .file_stem()
+    .unwrap_or_else(|| std::ffi::OsStr::new("output"))
+    .to_os_string();
+    name.push(format!(".{}", suffix));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Matches file name against pattern with `*` (any characters) and `?` (one character).
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| wildcard_match(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && wildcard_match(rest, &name[1..])
+    }
+}
+
+/// Expands inputs with wildcards in file name into sorted matching files.
+/// Inputs without wildcards are kept as they are.
+fn expand_inputs(inputs: &[String]) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+
+    for input in inputs {
+        let path = Path::new(input);
+        let pattern: Vec<char> = match path.file_name() {
+            Some(name) if name.to_string_lossy().contains(['*', '?']) => name.to_string_lossy().chars().collect(),
+            _ => {
+                paths.push(path.to_path_buf());
+                continue;
+            }
+        };
 
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new(".")
+        };
+        let mut matches: Vec<PathBuf> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {:?}", dir))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .filter(|entry| wildcard_match(&pattern, &entry.file_name().to_string_lossy().chars().collect::<Vec<_>>()))
+        .map(|entry| dir.join(entry.file_name()))
+        .collect();
+
+        if matches.is_empty() {
+            anyhow::bail!("No files match {:?}", input);
+        }
+        matches.sort();
+        paths.append(&mut matches);
+    }
+
+    Ok(paths)
+}
+
+/// Output path of input without `--output`: file stem of the input with extension of the
+/// format, in `--out-dir` or current directory.
+fn default_output_path(args: &Args, input: &Path) -> Result<PathBuf> {
+    let mut default_name = input
+    .file_stem()
+    .filter(|_| input.as_os_str() != "-")
+    .unwrap_or_else(|| std::ffi::OsStr::new("output"))
+    .to_os_string();
+    default_name.push(".");
+    default_name.push(args.format.extension());
+    Ok(match &args.out_dir {
+        Some(dir) => dir.join(default_name),
+        None => std::env::current_dir()?.join(default_name)
+    })
+}
+
+/// Output paths of batch inputs. Input whose output path is taken by an earlier input
+/// (same file name in another directory) gets `Err` with that input instead, so it doesn't
+/// overwrite its output.
+fn batch_output_paths<'a>(args: &Args, inputs: &'a [PathBuf]) -> Result<Vec<std::result::Result<PathBuf, &'a Path>>> {
+    let mut taken: HashMap<PathBuf, &Path> = HashMap::new();
+    inputs.iter().map(|input| {
+        let path = default_output_path(args, input)?;
+        Ok(match taken.get(&path) {
+            Some(&first) => Err(first),
+            None => {
+                taken.insert(path.clone(), input);
+                Ok(path)
+            }
+        })
+    }).collect()
+}
+
+/// Converts one MIDI file and writes its buildings, returns paths of written files.
+fn convert(args: &Args, input: &Path, output: Option<&Path>) -> Result<Vec<PathBuf>> {
     // Read MIDI file
     let mut buffer = Vec::new();
-    if args.input == PathBuf::from("-") {
+    if input.as_os_str() == "-" {
         std::io::stdin().read_to_end(&mut buffer)
-        .with_context(|| format!("Failed to read input file {:?}", args.input))?;
+        .context("Failed to read MIDI from stdin")?;
+        if buffer.is_empty() {
+            anyhow::bail!("No MIDI data on stdin");
+        }
     } else {
-        File::open(&args.input)
-        .with_context(|| format!("Failed to open input file {:?}", args.input))?
+        File::open(input)
+        .with_context(|| format!("Failed to open input file {:?}", input))?
         .read_to_end(&mut buffer)
-        .with_context(|| format!("Failed to read input file {:?}", args.input))?;
+        .with_context(|| format!("Failed to read input file {:?}", input))?;
     }
 
     let smf = Smf::parse(&buffer)
-    .with_context(|| format!("Failed to parse MIDI file {:?}", args.input))?;
+    .with_context(|| format!("Failed to parse MIDI file {:?}", input))?;
+
+    if args.list_tracks {
+        print_track_list(&smf);
+        return Ok(Vec::new());
+    }
 
     // Generate building
-    let building = generate_music_player(
-        smf,
-        args.notes_per_value,
-        args.min_pitch,
-        args.max_pitch,
-        args.min_velocity,
-        args.repeat,
-        args.max_events_per_func,
-    ).with_context(|| format!("Failed to generate building"))?;
+    let (buildings, stats) = generate_music_players_with_stats(smf, &args.player_options())
+    .context("Failed to generate building")?;
+
+    if args.stats {
+        eprintln!("Blocks: {}", stats.block_count);
+        eprintln!("Channels: {}", stats.channel_count);
+        eprintln!("Used notes: {}", stats.used_note_count);
+        eprintln!("Tempo changes: {}", stats.tempo_change_count);
+        eprintln!("Length (ticks): {}", stats.total_len);
+    }
+
+    if stats.dropped_note_count > 0 {
+        eprintln!(
+            "Warning: {} notes were out of pitch range ({}..={}) and dropped",
+            stats.dropped_note_count,
+            args.min_pitch,
+            args.max_pitch
+        );
+    }
+
+    // JSON doesn't depend on structure version, so it is written once.
+    let versions = match args.format {
+        OutputFormat::Structure => &args.structure_version[..],
+        OutputFormat::Json => &args.structure_version[..1]
+    };
+
+    if args.dry_run {
+        for (i, building) in buildings.iter().enumerate() {
+            for &version in versions {
+                let mut buffer = Vec::new();
+                write_output(&mut buffer, building, args.format, version)?;
+                eprintln!("Building {} (version {}): {} blocks, {} bytes", i, version, building.blocks.len(), buffer.len());
+            }
+        }
+        return Ok(Vec::new());
+    }
 
     // Write output
     if args.stdout {
+        if buildings.len() > 1 {
+            anyhow::bail!("Player was split into {} buildings, which can't be written to stdout", buildings.len());
+        }
         let stdout = std::io::stdout();
         let mut handle = stdout.lock();
-        handle.write_building(&building, args.structure_version).with_context(|| format!("Failed to serialize building"))?;
-    } else {
-        let output_path = match args.output {
-            Some(p) => p,
-            None => {
-                let mut default_name = args
-                .input
-                .file_stem()
-                .unwrap_or_else(|| std::ffi::OsStr::new("output"))
-                .to_os_string();
-                default_name.push(".structure");
-                std::env::current_dir()?.join(default_name)
-            }
+        write_output(&mut handle, &buildings[0], args.format, versions[0])?;
+        return Ok(Vec::new());
+    }
+
+    let output_path = match output {
+        Some(p) => p.to_path_buf(),
+        None => default_output_path(args, input)?
+    };
+
+    // Building is generated once and only serialized for every version.
+    let mut written = Vec::with_capacity(buildings.len() * versions.len());
+    for (i, building) in buildings.iter().enumerate() {
+        let building_path = if buildings.len() > 1 { suffixed_path(&output_path, i) } else { output_path.clone() };
+
+        for &version in versions {
+            let path = if versions.len() > 1 { suffixed_path(&building_path, format_args!("v{}", version)) } else { building_path.clone() };
+
+            let mut output_file = File::create(&path)
+            .with_context(|| format!("Failed to create output file {:?}", path))?;
+
+            write_output(&mut output_file, building, args.format, version)?;
+            written.push(path);
+        }
+    }
+
+    Ok(written)
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    args.validate()?;
+
+    let inputs = expand_inputs(&args.inputs)?;
+
+    if let Some(dir) = &args.out_dir {
+        std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create output directory {:?}", dir))?;
+    }
+
+    if let [input] = &inputs[..] {
+        for path in convert(&args, input, args.output.as_deref())? {
+            println!("Wrote structure file to {:?}", path);
+        }
+        return Ok(());
+    }
+
+    // Batch mode: errors are reported per file without stopping the rest.
+    let output_paths = batch_output_paths(&args, &inputs)?;
+    let mut failed = 0;
+    for (input, output_path) in inputs.iter().zip(output_paths) {
+        if args.list_tracks {
+            println!("{:?}", input);
+        }
+        // Nothing is written when only listing tracks or measuring output.
+        let output_path = match output_path {
+            Err(first) if !args.list_tracks && !args.dry_run => {
+                eprintln!("{:?}: output file has the same name as output of {:?}, convert it separately with --output", input, first);
+                failed += 1;
+                continue;
+            },
+            Ok(path) => Some(path),
+            Err(_) => None
         };
-        let mut output_file = File::create(&output_path)
-        .with_context(|| format!("Failed to create output file {:?}", output_path))?;
+        match convert(&args, input, output_path.as_deref()) {
+            Ok(paths) => for path in paths {
+                println!("Wrote structure file to {:?}", path);
+            },
+            Err(e) => {
+                eprintln!("{:?}: {:#}", input, e);
+                failed += 1;
+            }
+        }
+    }
 
-        output_file
-        .write_building(&building, args.structure_version).with_context(|| format!("Failed to serialize building"))?;
-        println!("Wrote structure file to {:?}", output_path);
+    println!();
+    println!("Converted {} of {} files", inputs.len() - failed, inputs.len());
+    if failed > 0 {
+        anyhow::bail!("Failed to convert {} files", failed);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Args {
+        Args::try_parse_from(["midi2swstruct"].iter().chain(args)).unwrap()
+    }
+
+    #[test]
+    fn validate_accepts_defaults() {
+        assert!(parse(&["song.mid"]).validate().is_ok());
+        assert!(parse(&["song.mid", "--min-pitch", "60", "--max-pitch", "60"]).validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_inverted_pitch_range() {
+        let error = parse(&["song.mid", "--min-pitch", "100", "--max-pitch", "40"]).validate().unwrap_err();
+        assert!(error.to_string().contains("--min-pitch (100) is greater than --max-pitch (40)"), "{}", error);
+    }
+
+    #[test]
+    fn out_of_range_arguments_are_rejected_by_parser() {
+        assert!(Args::try_parse_from(["midi2swstruct", "song.mid", "--max-pitch", "128"]).is_err());
+        assert!(Args::try_parse_from(["midi2swstruct", "song.mid", "--min-velocity", "128"]).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_single_output_for_several_inputs() {
+        assert!(parse(&["a.mid", "b.mid", "--output", "out.structure"]).validate().is_err());
+        assert!(parse(&["*.mid", "--stdout"]).validate().is_err());
+        assert!(parse(&["a.mid", "b.mid", "--out-dir", "out"]).validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_invalid_tuning() {
+        for tuning in ["--tuning=0", "--tuning=-432", "--tuning=NaN", "--tuning=inf"] {
+            assert!(parse(&["song.mid", tuning]).validate().is_err(), "{}", tuning);
+        }
+        assert!(parse(&["song.mid", "--tuning", "415"]).validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_non_finite_volume() {
+        assert!(parse(&["song.mid", "--volume", "NaN"]).validate().is_err());
+        assert!(parse(&["song.mid", "--volume", "inf"]).validate().is_err());
+        // Finite volumes out of range are clamped by the library.
+        assert!(parse(&["song.mid", "--volume", "150"]).validate().is_ok());
+    }
+
+    #[test]
+    fn batch_inputs_with_same_name_get_one_output() {
+        let args = parse(&["a/song.mid", "b/song.mid", "c/other.mid", "--out-dir", "out"]);
+        let inputs: Vec<PathBuf> = ["a/song.mid", "b/song.mid", "c/other.mid"].map(PathBuf::from).to_vec();
+
+        let paths = batch_output_paths(&args, &inputs).unwrap();
+        assert_eq!(paths, vec![
+            Ok(Path::new("out/song.structure").to_path_buf()),
+            Err(Path::new("a/song.mid")),
+            Ok(Path::new("out/other.structure").to_path_buf())
+        ]);
+    }
+}